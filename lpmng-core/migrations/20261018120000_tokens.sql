@@ -0,0 +1,10 @@
+create table if not exists tokens
+(
+    revocation_id   text                        not null primary key,
+    user_id         uuid                        not null,
+    issued_at       timestamp default now()     not null,
+    expires_at      timestamp                   not null,
+    revoked_at      timestamp
+);
+
+create index if not exists tokens_user_id_idx on tokens (user_id);
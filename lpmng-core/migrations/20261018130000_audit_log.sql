@@ -0,0 +1,11 @@
+create table if not exists audit_log
+(
+    id          uuid      default gen_random_uuid() not null primary key,
+    date_time   timestamp default now()             not null,
+    user_id     uuid,
+    action      text                                not null,
+    ip          text,
+    detail      text
+);
+
+create index if not exists audit_log_user_id_idx on audit_log (user_id);
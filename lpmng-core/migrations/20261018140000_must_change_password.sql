@@ -0,0 +1,2 @@
+alter table users
+    add column if not exists must_change_password boolean default false not null;
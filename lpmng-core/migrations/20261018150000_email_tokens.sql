@@ -0,0 +1,14 @@
+alter table users
+    add column if not exists email_verified boolean default false not null;
+
+create table if not exists email_tokens
+(
+    token_hash      text                        not null primary key,
+    user_id         uuid                        not null references users on delete cascade,
+    purpose         text                        not null,
+    email           text                        not null,
+    expires_at      timestamp                   not null,
+    used_at         timestamp
+);
+
+create index if not exists email_tokens_user_id_idx on email_tokens (user_id);
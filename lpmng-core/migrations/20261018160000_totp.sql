@@ -0,0 +1,13 @@
+alter table users
+    add column if not exists totp_secret text,
+    add column if not exists totp_enabled boolean default false not null,
+    add column if not exists totp_last_step bigint;
+
+create table if not exists recovery_codes
+(
+    code_hash       text                        not null primary key,
+    user_id         uuid                        not null references users on delete cascade,
+    used_at         timestamp
+);
+
+create index if not exists recovery_codes_user_id_idx on recovery_codes (user_id);
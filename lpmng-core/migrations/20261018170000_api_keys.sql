@@ -0,0 +1,10 @@
+create table if not exists api_keys
+(
+    id              uuid                        not null primary key,
+    name            text                        not null,
+    created_by      uuid                        not null references users on delete cascade,
+    operations      text[]                      not null,
+    revocation_id   text                        not null references tokens,
+    created_at      timestamp default now()     not null,
+    expires_at      timestamp
+);
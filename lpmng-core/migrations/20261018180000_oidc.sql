@@ -0,0 +1,7 @@
+alter table users
+    add column if not exists oidc_subject text unique;
+
+-- accounts created through the identity provider may not have a phone yet
+alter table users
+    drop constraint if exists users_phone_key;
+create unique index if not exists users_phone_key on users (phone) where phone <> '';
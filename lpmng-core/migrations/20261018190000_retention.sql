@@ -0,0 +1,12 @@
+alter table users
+    add column if not exists anonymized_at timestamp;
+
+-- single row holding when the personal data of participants is anonymized
+create table if not exists retention
+(
+    id              boolean default true        not null primary key check (id),
+    event_end       date,
+    retention_days  integer default 30          not null
+);
+
+insert into retention (id) values (true) on conflict do nothing;
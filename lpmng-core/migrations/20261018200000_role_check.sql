@@ -0,0 +1,9 @@
+-- roles are a closed set, accounts with a mistyped role could never log in
+update users
+set role = 'user'
+where role not in ('user', 'helpdesk', 'staff', 'admin');
+
+alter table users
+    drop constraint if exists users_role_check;
+alter table users
+    add constraint users_role_check check (role in ('user', 'helpdesk', 'staff', 'admin'));
@@ -0,0 +1,33 @@
+-- phones are stored in E.164 so the same number written differently is caught
+-- as a duplicate, numbers so far could only be french mobiles
+drop index if exists users_phone_key;
+
+update users
+set phone = regexp_replace(phone, '[\s.]', '', 'g')
+where phone <> '';
+
+update users
+set phone = '+33' || substr(phone, 2)
+where phone ~ '^0[67][0-9]{8}$';
+
+-- the same number registered twice in different forms, there is no telling
+-- which account owns it so they have to be fixed by hand first
+do $$
+declare
+    duplicates text;
+begin
+    select string_agg(phone || ' (' || usernames || ')', ', ')
+    into duplicates
+    from (select phone, string_agg(username, ', ' order by username) as usernames
+          from users
+          where phone <> ''
+          group by phone
+          having count(*) > 1) d;
+
+    if duplicates is not null then
+        raise exception 'phones used by several users: %', duplicates;
+    end if;
+end
+$$;
+
+create unique index users_phone_key on users (phone) where phone <> '';
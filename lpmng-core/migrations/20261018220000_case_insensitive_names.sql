@@ -0,0 +1,9 @@
+-- usernames and emails are unique whatever their case, existing accounts
+-- only differing by case have to be merged before this applies
+alter table users
+    drop constraint if exists users_username_key;
+create unique index users_username_key on users (lower(username));
+
+alter table users
+    drop constraint if exists users_email_key;
+create unique index users_email_key on users (lower(email));
@@ -0,0 +1,3 @@
+-- devices a user may register, the role or global quota applies when unset
+alter table users
+    add column if not exists max_devices integer check (max_devices >= 0);
@@ -0,0 +1,7 @@
+-- api keys are not sessions of their creator, their tokens have no user so
+-- revoking a user's sessions leaves them alone
+alter table tokens
+    alter column user_id drop not null;
+
+update tokens set user_id = null
+where revocation_id in (select revocation_id from api_keys);
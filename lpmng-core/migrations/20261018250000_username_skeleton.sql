@@ -0,0 +1,9 @@
+-- usernames are unique by their skeleton, so names only differing by
+-- characters looking alike (a cyrillic а for a latin a, 0 for O) are the same.
+-- It is computed by the server, which also brings names and emails written
+-- before their normalization to their normalized form on startup.
+alter table users
+    add column if not exists username_skeleton text;
+
+drop index if exists users_username_key;
+create unique index users_username_key on users (username_skeleton);
@@ -0,0 +1,3 @@
+-- internet cut for a single device, allowing its owner again leaves it off
+alter table devices
+    add column if not exists disabled boolean not null default false;
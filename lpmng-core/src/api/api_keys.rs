@@ -0,0 +1,127 @@
+use crate::api::{bearer, is_authorized, json_body, with_handler, ApiHandler};
+use crate::error::Error::{ApiKeyDoesNotExist, Forbidden, UnknownOperation};
+use crate::model::api_key::{ApiKeyInput, CreatedApiKey, NewApiKey};
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+fn is_operation(op: &str) -> bool {
+    op.split_once(':').is_some_and(|(resource, action)| {
+        [resource, action]
+            .iter()
+            .all(|e| !e.is_empty() && e.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+    })
+}
+
+async fn get_api_keys(
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "api_keys:manage", None)? {
+        Err(Forbidden)?;
+    }
+
+    Ok(warp::reply::json(&handler.db.get_api_keys().await?))
+}
+
+/// Creates a key restricted to operations its creator is allowed to do.
+async fn create_api_key(
+    input: ApiKeyInput,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token.clone(), &handler.auth, "api_keys:manage", None)? {
+        Err(Forbidden)?;
+    }
+    // keys need an account as creator, so not another key nor the break-glass login
+    let claims = handler
+        .auth
+        .claims(bearer(auth_token.clone())?)
+        .map_err(|_| Forbidden)?;
+    if claims.id.is_nil() {
+        Err(Forbidden)?;
+    }
+
+    if input.operations.is_empty() {
+        Err(UnknownOperation(String::new()))?;
+    }
+    for op in &input.operations {
+        if !is_operation(op) {
+            Err(UnknownOperation(op.clone()))?;
+        }
+        if !is_authorized(auth_token.clone(), &handler.auth, op, None)? {
+            Err(Forbidden)?;
+        }
+    }
+
+    let id = Uuid::new_v4();
+    let (key, revocation_id) =
+        handler
+            .auth
+            .build_api_key(&claims, id, &input.operations, input.expires_at)?;
+
+    let name: String = input.name.into();
+    handler
+        .db
+        .insert_api_key(NewApiKey {
+            id,
+            name: name.clone(),
+            created_by: claims.id,
+            operations: input.operations,
+            revocation_id,
+            expires_at: input.expires_at.map(|e| e.naive_utc()),
+        })
+        .await?;
+    handler
+        .db
+        .insert_audit("api_key_created", Some(claims.id), None, Some(name))
+        .await?;
+
+    Ok(warp::reply::json(&CreatedApiKey { id, key }))
+}
+
+async fn revoke_api_key(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "api_keys:manage", None)? {
+        Err(Forbidden)?;
+    }
+
+    let revocation_id = handler
+        .db
+        .get_api_key_revocation_id(id)
+        .await?
+        .ok_or(ApiKeyDoesNotExist)?;
+
+    handler.db.revoke_token(revocation_id.clone()).await?;
+    handler.auth.revoke(vec![revocation_id]);
+
+    Ok(warp::reply())
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("api-keys"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(get_api_keys);
+
+    let post = warp::post()
+        .and(warp::path!("api-keys"))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(create_api_key);
+
+    let delete = warp::delete()
+        .and(warp::path!("api-keys" / Uuid))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(revoke_api_key);
+
+    list.or(post).or(delete)
+}
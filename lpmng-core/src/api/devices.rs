@@ -1,7 +1,11 @@
-use crate::api::{is_admin, is_user, trace_router_response, with_handler, ApiHandler};
+use crate::api::{is_authorized, json_body, trace_router_response, with_handler, ApiHandler};
 use crate::error::Error;
-use crate::error::Error::{Forbidden, NotRunningBehindAProxy, UserDoesNotExist};
-use crate::model::device::{Device, DeviceInput, NewDevice};
+use crate::error::Error::{
+    Conflict, DeviceDoesNotExist, Forbidden, NotRunningBehindAProxy, UserDoesNotExist,
+};
+use crate::model::device::{
+    Device, DeviceInput, DevicePatch, MacDeviceInput, NewDevice, QuotaInput, QuotaUsage,
+};
 use chrono::Utc;
 use futures::FutureExt;
 use lpmng_mq::client::agent::RouterRequest;
@@ -16,7 +20,7 @@ async fn get_devices(
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl Reply, Rejection> {
-    if !is_admin(auth_token, &handler.auth_key)? {
+    if !is_authorized(auth_token, &handler.auth, "devices:list", None)? {
         Err(Forbidden)?;
     }
 
@@ -30,8 +34,7 @@ async fn get_device_by_user(
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl Reply, Rejection> {
-    let auth_key = &handler.auth_key;
-    if !is_admin(auth_token.clone(), auth_key)? && !is_user(id, auth_token, auth_key)? {
+    if !is_authorized(auth_token, &handler.auth, "devices:read", Some(id))? {
         Err(Forbidden)?;
     }
 
@@ -40,62 +43,52 @@ async fn get_device_by_user(
     ))
 }
 
-pub async fn add_device(
-    device: DeviceInput,
-    auth_token: String,
-    ip: String,
-    handler: Arc<ApiHandler>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    if !is_user(device.user_id, auth_token, &handler.auth_key)? {
-        Err(Forbidden)?;
-    }
-
-    let ip = if ip.contains(",") {
-        ip.split(",").next().unwrap().to_string()
-    } else {
-        ip
-    };
-    if ip.is_empty() {
-        Err(NotRunningBehindAProxy)?;
-    }
-
-    let mac = handler
-        .mac_handler
-        .get_mac_from_ip(Ipv4Addr::from_str(&ip).map_err(Into::<Error>::into)?)
-        .await?;
-
-    let device = NewDevice {
-        mac: mac.clone(),
-        user_id: device.user_id,
-        internet: false,
-        date_time: Utc::now().naive_utc(),
-    };
-
-    let old_device = handler.db.get_device_by_mac(device.mac.clone()).await?;
+/// Registers a device for a user, giving it internet when the user is
+/// allowed. A MAC already registered is only updated for the same user.
+async fn register_mac(
+    handler: &ApiHandler,
+    user_id: Uuid,
+    mac: String,
+) -> crate::error::Result<()> {
+    let old_device = handler.db.get_device_by_mac(mac.clone()).await?;
 
     if let Some(old_device) = &old_device {
-        if old_device.user_id != device.user_id {
+        if old_device.user_id != user_id {
             error!(
-                current_user = device.user_id.as_hyphenated().to_string(),
+                current_user = user_id.as_hyphenated().to_string(),
                 existing_device_user = old_device.user_id.as_hyphenated().to_string(),
-                device_mac = device.mac,
+                device_mac = mac,
                 "dubious: device does not belong to the current user"
             );
             Err(Forbidden)?;
         }
     }
 
-    let authorized = handler
+    let user = handler
         .db
-        .get_user(device.user_id)
+        .get_user(user_id)
         .await?
-        .ok_or(UserDoesNotExist)?
-        .is_allowed;
+        .ok_or(UserDoesNotExist)?;
+    let authorized = user.is_allowed;
 
     match old_device {
         None => {
+            let max = handler.device_quota.limit(user.role, user.max_devices);
+            let id = handler
+                .db
+                .insert_device(
+                    NewDevice {
+                        mac: mac.clone(),
+                        user_id,
+                        internet: authorized,
+                        date_time: Utc::now().naive_utc(),
+                    },
+                    max,
+                )
+                .await?;
+
             if authorized {
-                handler
+                let res = handler
                     .router
                     .lock()
                     .await
@@ -104,19 +97,16 @@ pub async fn add_device(
                         body: mac.clone(),
                     })
                     .map(trace_router_response)
-                    .await?;
+                    .await;
+                // the place is only taken once the router let the device in
+                if let Err(error) = res {
+                    handler.db.delete_device(id).await?;
+                    Err(error)?;
+                }
             }
-            handler
-                .db
-                .insert_device(NewDevice {
-                    mac: mac.clone(),
-                    user_id: device.user_id,
-                    internet: authorized,
-                    date_time: Utc::now().naive_utc(),
-                })
-                .await?;
         }
         Some(old_device) => {
+            let authorized = authorized && !old_device.disabled;
             match (authorized, old_device.internet) {
                 (false, true) => {
                     handler
@@ -154,18 +144,234 @@ pub async fn add_device(
                         user_id: old_device.user_id,
                         internet: authorized,
                         date_time: Utc::now().naive_utc(),
+                        disabled: old_device.disabled,
                     })
                     .await?;
             }
         }
     }
 
+    Ok(())
+}
+
+pub async fn add_device(
+    device: DeviceInput,
+    auth_token: String,
+    ip: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_authorized(
+        auth_token,
+        &handler.auth,
+        "devices:add",
+        Some(device.user_id),
+    )? {
+        Err(Forbidden)?;
+    }
+
+    let ip = handler.client_ip(&ip);
+    if ip.is_empty() {
+        Err(NotRunningBehindAProxy)?;
+    }
+
+    let mac = handler
+        .mac_handler
+        .get_mac_from_ip(Ipv4Addr::from_str(ip).map_err(Into::<Error>::into)?)
+        .await?;
+
+    register_mac(&handler, device.user_id, mac).await?;
+
+    Ok(warp::reply())
+}
+
+/// Registers a device by its MAC, for consoles and machines without a
+/// browser to log in from.
+async fn register_device(
+    device: MacDeviceInput,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(
+        auth_token,
+        &handler.auth,
+        "devices:register",
+        Some(device.user_id),
+    )? {
+        Err(Forbidden)?;
+    }
+
+    // unlike a user adding the device they browse from, an admin typing a
+    // MAC owned by someone else is a mistake to report, not a dubious request
+    if handler
+        .db
+        .get_device_by_mac(device.mac.to_string())
+        .await?
+        .is_some_and(|e| e.user_id != device.user_id)
+    {
+        Err(Conflict("mac".into()))?;
+    }
+
+    register_mac(&handler, device.user_id, device.mac.to_string()).await?;
+    handler
+        .db
+        .insert_audit(
+            "device_registered",
+            Some(device.user_id),
+            None,
+            Some(device.mac.into()),
+        )
+        .await?;
+
+    Ok(warp::reply())
+}
+
+async fn delete_device(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    // without the right on every device, a missing one is refused like one
+    // of another user so ids cannot be probed
+    let device = handler.db.get_device(id).await?;
+    if !is_authorized(
+        auth_token,
+        &handler.auth,
+        "devices:delete",
+        device.as_ref().map(|e| e.user_id),
+    )? {
+        Err(Forbidden)?;
+    }
+    let device = device.ok_or(DeviceDoesNotExist)?;
+
+    if device.internet {
+        handler
+            .router
+            .lock()
+            .await
+            .send(RouterRequest {
+                action: "remove".to_string(),
+                body: device.mac.clone(),
+            })
+            .map(trace_router_response)
+            .await?;
+    }
+    handler.db.delete_device(id).await?;
+    handler
+        .db
+        .insert_audit(
+            "device_deleted",
+            Some(device.user_id),
+            None,
+            Some(device.mac),
+        )
+        .await?;
+
+    Ok(warp::reply())
+}
+
+/// Gives or takes internet to a single device, the owner keeping their
+/// `is_allowed`. A device taken internet stays off when its owner is
+/// allowed again.
+async fn patch_device(
+    id: Uuid,
+    patch: DevicePatch,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "devices:write", None)? {
+        Err(Forbidden)?;
+    }
+
+    let device = handler.db.get_device(id).await?.ok_or(DeviceDoesNotExist)?;
+
+    if patch.internet != device.internet {
+        handler
+            .router
+            .lock()
+            .await
+            .send(RouterRequest {
+                action: if patch.internet { "add" } else { "remove" }.to_string(),
+                body: device.mac.clone(),
+            })
+            .map(trace_router_response)
+            .await?;
+    }
+    if patch.internet != device.internet || patch.internet == device.disabled {
+        handler
+            .db
+            .update_device(Device {
+                internet: patch.internet,
+                disabled: !patch.internet,
+                ..device
+            })
+            .await?;
+    }
+
+    Ok(warp::reply())
+}
+
+/// Lists the users having as many devices as their quota allows, or more
+/// when it was lowered since.
+async fn get_quota_usage(
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "devices:quota", None)? {
+        Err(Forbidden)?;
+    }
+
+    let res = handler
+        .db
+        .get_device_counts()
+        .await?
+        .into_iter()
+        .map(|e| QuotaUsage {
+            max_devices: handler.device_quota.limit(e.role, e.max_devices),
+            user_id: e.user_id,
+            username: e.username,
+            devices: e.devices,
+        })
+        .filter(|e| e.devices >= e.max_devices)
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&res))
+}
+
+async fn set_quota(
+    id: Uuid,
+    quota: QuotaInput,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "devices:quota", None)? {
+        Err(Forbidden)?;
+    }
+
+    handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    handler
+        .db
+        .set_max_devices(id, quota.max_devices.map(Into::into))
+        .await?;
+
     Ok(warp::reply())
 }
 
 pub(super) fn routes(
     handler: Arc<ApiHandler>,
 ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
+    let quotas = warp::get()
+        .and(warp::path!("devices" / "quotas"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(get_quota_usage);
+
+    let set_quota = warp::put()
+        .and(warp::path!("devices" / "quotas" / Uuid))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(set_quota);
+
     let list = warp::get()
         .and(warp::path("devices"))
         .and(warp::header::<String>("Authorization"))
@@ -179,13 +385,40 @@ pub(super) fn routes(
         .and(with_handler(handler.clone()))
         .and_then(get_device_by_user);
 
+    let register = warp::post()
+        .and(warp::path!("devices" / "mac"))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(register_device);
+
     let post = warp::post()
         .and(warp::path("devices"))
-        .and(warp::body::json())
+        .and(json_body())
         .and(warp::header::<String>("Authorization"))
         .and(warp::header::<String>("X-Forwarded-For"))
-        .and(with_handler(handler))
+        .and(with_handler(handler.clone()))
         .and_then(add_device);
 
-    get.or(list).or(post)
+    let delete = warp::delete()
+        .and(warp::path!("devices" / Uuid))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(delete_device);
+
+    let patch = warp::patch()
+        .and(warp::path!("devices" / Uuid))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(patch_device);
+
+    quotas
+        .or(set_quota)
+        .or(delete)
+        .or(patch)
+        .or(get)
+        .or(list)
+        .or(register)
+        .or(post)
 }
@@ -0,0 +1,194 @@
+use crate::api::{is_authorized, json_body, revoke_sessions, with_handler, ApiHandler};
+use crate::auth::{generate_email_token, hash, token_digest};
+use crate::error::Error::{Forbidden, InvalidEmailToken, UserDoesNotExist};
+use crate::mailer::Mail;
+use crate::model::user::{EmailToken, PasswordForgot, PasswordReset};
+use chrono::{Duration, Utc};
+use std::sync::Arc;
+use tracing::error;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+const VERIFY_EMAIL: &str = "verify_email";
+const RESET_PASSWORD: &str = "reset_password";
+
+/// Stores a new single use token and mails a link to the front page handling it.
+async fn send_token(
+    handler: &ApiHandler,
+    user_id: Uuid,
+    email: String,
+    purpose: &str,
+    ttl: Duration,
+) -> crate::error::Result<()> {
+    let token = generate_email_token();
+    handler
+        .db
+        .insert_email_token(
+            token_digest(&token),
+            user_id,
+            purpose,
+            email.clone(),
+            (Utc::now() + ttl).naive_utc(),
+        )
+        .await?;
+
+    let mail = match purpose {
+        VERIFY_EMAIL => Mail {
+            to: email,
+            subject: "Verify your email address".into(),
+            body: format!(
+                "Open this link to verify your email address:\n{}/verify-email?token={token}",
+                handler.public_url
+            ),
+        },
+        _ => Mail {
+            to: email,
+            subject: "Reset your password".into(),
+            body: format!(
+                "Open this link to choose a new password:\n{}/reset-password?token={token}\n\nIgnore this mail if you did not ask for it.",
+                handler.public_url
+            ),
+        },
+    };
+
+    handler.mailer.send(mail).await
+}
+
+/// Sends the verification mail of a new or changed address. Failures are only
+/// logged since the user can ask for another mail.
+pub(super) async fn send_verification(handler: &ApiHandler, user_id: Uuid, email: String) {
+    if let Err(error) = send_token(
+        handler,
+        user_id,
+        email,
+        VERIFY_EMAIL,
+        handler.verify_token_ttl,
+    )
+    .await
+    {
+        error!(?error, %user_id, "failed to send the verification mail");
+    }
+}
+
+async fn verify_email(
+    token: EmailToken,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let (user_id, email) = handler
+        .db
+        .use_email_token(token_digest(&token.token), VERIFY_EMAIL)
+        .await?
+        .ok_or(InvalidEmailToken)?;
+
+    if !handler.db.verify_email(user_id, email).await? {
+        Err(InvalidEmailToken)?;
+    }
+
+    Ok(warp::reply())
+}
+
+async fn resend_verification(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:verify", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    if !u.email_verified {
+        send_token(
+            &handler,
+            id,
+            u.email,
+            VERIFY_EMAIL,
+            handler.verify_token_ttl,
+        )
+        .await?;
+    }
+
+    Ok(warp::reply())
+}
+
+async fn send_reset(handler: &ApiHandler, email: String) -> crate::error::Result<()> {
+    if let Some(id) = handler.db.get_user_id_by_email(email.clone()).await? {
+        send_token(handler, id, email, RESET_PASSWORD, handler.reset_token_ttl).await?;
+    }
+
+    Ok(())
+}
+
+async fn forgot_password(
+    forgot: PasswordForgot,
+    forwarded_for: Option<String>,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let email: String = forgot.email.into();
+    let ip = forwarded_for
+        .as_deref()
+        .map(|e| handler.client_ip(e).to_string());
+
+    handler.reset_requests.check(&email, ip.as_deref())?;
+    handler.reset_requests.failure(&email, ip.as_deref());
+
+    // always answer the same way and at once so the route does not reveal
+    // which addresses are registered
+    tokio::spawn(async move {
+        if let Err(error) = send_reset(&handler, email).await {
+            error!(?error, "failed to send the password reset mail");
+        }
+    });
+
+    Ok(warp::reply())
+}
+
+async fn reset_password(
+    reset: PasswordReset,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let (user_id, _) = handler
+        .db
+        .use_email_token(token_digest(&reset.token), RESET_PASSWORD)
+        .await?
+        .ok_or(InvalidEmailToken)?;
+
+    handler
+        .db
+        .update_password(user_id, hash(reset.password.to_string()), false)
+        .await?;
+    revoke_sessions(&handler, user_id).await?;
+
+    Ok(warp::reply())
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let verify = warp::post()
+        .and(warp::path!("email" / "verify"))
+        .and(json_body())
+        .and(with_handler(handler.clone()))
+        .and_then(verify_email);
+
+    let resend = warp::post()
+        .and(warp::path!("users" / Uuid / "email" / "verify"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(resend_verification);
+
+    let forgot = warp::post()
+        .and(warp::path!("password" / "forgot"))
+        .and(json_body())
+        .and(warp::header::optional::<String>("X-Forwarded-For"))
+        .and(with_handler(handler.clone()))
+        .and_then(forgot_password);
+
+    let reset = warp::post()
+        .and(warp::path!("password" / "reset"))
+        .and(json_body())
+        .and(with_handler(handler))
+        .and_then(reset_password);
+
+    verify.or(resend).or(forgot).or(reset)
+}
@@ -0,0 +1,70 @@
+use crate::api::{bearer, is_authorized, with_handler, ApiHandler};
+use crate::error::Error::Forbidden;
+use crate::export::{columns, header, line};
+use crate::model::export::{ExportFormat, ExportQuery};
+use futures::{future, stream, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use warp::http::{header as headers, Response};
+use warp::hyper::Body;
+use warp::{Filter, Rejection, Reply};
+
+/// Streams the users joined with their devices as CSV or NDJSON.
+async fn export_users(
+    query: ExportQuery,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token.clone(), &handler.auth, "users:export", None)? {
+        Err(Forbidden)?;
+    }
+    let (caller, _) = handler
+        .auth
+        .caller(bearer(auth_token)?, "users:export")
+        .map_err(|_| Forbidden)?;
+
+    let columns = columns(&query)?;
+    let format = query.format;
+    let first = header(format, &columns)?;
+    let names = columns
+        .iter()
+        .map(|e| e.name())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let rows = handler
+        .db
+        .export_users()
+        .map(move |row| row.and_then(|row| line(format, &columns, &row)));
+    let body = stream::once(future::ready(Ok(first)))
+        .chain(rows)
+        .map_err(|e| std::io::Error::other(format!("{e:?}")));
+
+    handler
+        .db
+        .insert_audit("users_exported", Some(caller), None, Some(names))
+        .await?;
+
+    let (content_type, file) = match format {
+        ExportFormat::Csv => ("text/csv", "users.csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "users.ndjson"),
+    };
+    Ok(Response::builder()
+        .header(headers::CONTENT_TYPE, content_type)
+        .header(
+            headers::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{file}\""),
+        )
+        .body(Body::wrap_stream(body))
+        .unwrap())
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("users" / "export"))
+        .and(warp::query::<ExportQuery>())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(export_users)
+}
@@ -0,0 +1,65 @@
+use crate::api::{bearer, is_authorized, with_handler, ApiHandler};
+use crate::error::Error::Forbidden;
+use crate::import::{parse, ParsedImport};
+use crate::model::import::{ImportMapping, ImportStatus};
+use std::sync::Arc;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+const MAX_CSV_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Creates or updates the users of a ticketing CSV export, the column names
+/// and the internet access of new accounts are given in the query string.
+async fn import_users(
+    mapping: ImportMapping,
+    body: Bytes,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token.clone(), &handler.auth, "users:import", None)? {
+        Err(Forbidden)?;
+    }
+    let (caller, _) = handler
+        .auth
+        .caller(bearer(auth_token)?, "users:import")
+        .map_err(|_| Forbidden)?;
+
+    let ParsedImport { rows, rejected } = parse(&body, &mapping)?;
+    let mut reports = handler
+        .db
+        .import_users(rows, mapping.is_allowed.unwrap_or(false))
+        .await?;
+    reports.extend(rejected);
+    reports.sort_by_key(|e| e.line);
+
+    let count = |status: ImportStatus| reports.iter().filter(|e| e.status == status).count();
+    handler
+        .db
+        .insert_audit(
+            "users_imported",
+            Some(caller),
+            None,
+            Some(format!(
+                "{} created, {} updated, {} rejected",
+                count(ImportStatus::Created),
+                count(ImportStatus::Updated),
+                count(ImportStatus::Rejected)
+            )),
+        )
+        .await?;
+
+    Ok(warp::reply::json(&reports))
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("users" / "import"))
+        .and(warp::query::<ImportMapping>())
+        .and(warp::body::content_length_limit(MAX_CSV_SIZE))
+        .and(warp::body::bytes())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(import_users)
+}
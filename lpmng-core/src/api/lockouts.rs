@@ -0,0 +1,51 @@
+use crate::api::{is_authorized, with_handler, ApiHandler};
+use crate::error::Error::{Forbidden, LockoutDoesNotExist};
+use crate::lockout::LockoutKind;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+async fn get_lockouts(
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "lockouts:list", None)? {
+        Err(Forbidden)?;
+    }
+
+    Ok(warp::reply::json(&handler.lockouts.list()))
+}
+
+async fn unlock(
+    kind: LockoutKind,
+    key: String,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "lockouts:unlock", None)? {
+        Err(Forbidden)?;
+    }
+
+    if !handler.lockouts.unlock(kind, key) {
+        Err(LockoutDoesNotExist)?;
+    }
+
+    Ok(warp::reply())
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path("lockouts"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(get_lockouts);
+
+    let delete = warp::delete()
+        .and(warp::path!("lockouts" / LockoutKind / String))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(unlock);
+
+    list.or(delete)
+}
@@ -1,42 +1,174 @@
-use crate::api::{with_handler, ApiHandler};
-use crate::auth::build_token;
-use crate::model::login::{Credentials, Login};
+use super::mfa::use_mfa_code;
+use crate::api::{bearer, issue, json_body, with_handler, ApiHandler};
+use crate::auth::Claims;
+use crate::error::Error::{InvalidCredential, InvalidMfaCode, UserDoesNotExist};
+use crate::model::login::{Login, MfaChallenge, MfaLogin};
+use crate::model::user::Role;
+use crate::model::utils::normalize_username;
+use chrono::Utc;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::warn;
 use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 
-async fn login(login: Login, handler: Arc<ApiHandler>) -> Result<impl Reply, Rejection> {
-    if *login.username == "admin" && *login.password == handler.admin_key {
-        let t = build_token("admin".into(), Uuid::nil(), &handler.auth_key)?;
-        return Ok(warp::reply::json(&Credentials {
-            biscuit: t,
-            role: "admin".into(),
-            user_id: Uuid::nil(),
-        }));
+async fn login(
+    login: Login,
+    forwarded_for: Option<String>,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let ip = forwarded_for
+        .as_deref()
+        .map(|e| handler.client_ip(e).to_string());
+    let username = normalize_username(&login.username);
+
+    handler.lockouts.check(&username, ip.as_deref())?;
+
+    // break-glass access when no admin account can log in, only enabled by setting BREAK_GLASS_KEY
+    if let Some(key) = &handler.break_glass_key {
+        if username == "admin" && bool::from(login.password.as_bytes().ct_eq(key.as_bytes())) {
+            warn!(ip, "break-glass admin login");
+            handler
+                .db
+                .insert_audit("break_glass_login", None, ip, None)
+                .await?;
+
+            let claims = Claims {
+                role: Role::Admin,
+                id: Uuid::nil(),
+                session: Utc::now(),
+                must_change_password: false,
+                // the key is the second factor of this login
+                mfa: true,
+            };
+            let t = handler.auth.build_token(&claims)?;
+            return Ok(warp::reply::json(&issue(&handler, t, &claims).await?));
+        }
     }
 
     let auth = handler
         .db
-        .check_password(login.username.to_string(), login.password.to_string())
+        .check_password(username.clone(), login.password.to_string())
+        .await;
+
+    if let Err(InvalidCredential) = auth {
+        handler.lockouts.failure(&username, ip.as_deref());
+    }
+    let u = auth?;
+
+    if u.totp_enabled {
+        // the lockout is only cleared once the second factor is checked
+        return Ok(warp::reply::json(&MfaChallenge {
+            mfa_token: handler.auth.build_mfa_token(u.id)?,
+        }));
+    }
+
+    handler.lockouts.success(&username, ip.as_deref());
+
+    handler
+        .db
+        .insert_audit("login", Some(u.id), ip, None)
         .await?;
 
-    let (role, id) = auth;
+    let claims = Claims {
+        role: u.role,
+        id: u.id,
+        session: Utc::now(),
+        must_change_password: u.must_change_password,
+        mfa: false,
+    };
+    let t = handler.auth.build_token(&claims)?;
+
+    Ok(warp::reply::json(&issue(&handler, t, &claims).await?))
+}
+
+/// Second step of a login, with a code from the authenticator or a recovery code.
+async fn login_mfa(
+    login: MfaLogin,
+    forwarded_for: Option<String>,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let ip = forwarded_for
+        .as_deref()
+        .map(|e| handler.client_ip(e).to_string());
+
+    let id = handler.auth.check_mfa_token(login.mfa_token)?;
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
 
-    let t = build_token(role.to_owned(), id, &handler.auth_key)?;
+    handler.lockouts.check(&u.username, ip.as_deref())?;
 
-    Ok(warp::reply::json(&Credentials {
-        biscuit: t,
-        role,
-        user_id: id,
-    }))
+    if !use_mfa_code(&handler, &u, &login.code).await? {
+        handler.lockouts.failure(&u.username, ip.as_deref());
+        Err(InvalidMfaCode)?;
+    }
+
+    handler.lockouts.success(&u.username, ip.as_deref());
+
+    handler.db.insert_audit("login", Some(id), ip, None).await?;
+
+    let claims = Claims {
+        role: u.role,
+        id,
+        session: Utc::now(),
+        must_change_password: u.must_change_password,
+        mfa: true,
+    };
+    let t = handler.auth.build_token(&claims)?;
+
+    Ok(warp::reply::json(&issue(&handler, t, &claims).await?))
+}
+
+/// Replaces a token with a fresh one. The old token is revoked so a session
+/// only ever has one valid token, the one a logout revokes.
+async fn refresh(auth_token: String, handler: Arc<ApiHandler>) -> Result<impl Reply, Rejection> {
+    let auth_token = bearer(auth_token)?;
+    let previous = handler.auth.revocation_id(auth_token.clone())?;
+    let (t, claims) = handler.auth.refresh_token(auth_token)?;
+    let credentials = issue(&handler, t, &claims).await?;
+
+    handler.db.revoke_token(previous.clone()).await?;
+    handler.auth.revoke(vec![previous]);
+
+    Ok(warp::reply::json(&credentials))
+}
+
+async fn logout(auth_token: String, handler: Arc<ApiHandler>) -> Result<impl Reply, Rejection> {
+    let revocation_id = handler.auth.revocation_id(bearer(auth_token)?)?;
+
+    handler.db.revoke_token(revocation_id.clone()).await?;
+    handler.auth.revoke(vec![revocation_id]);
+
+    Ok(warp::reply())
 }
 
 pub fn routes(
     handler: Arc<ApiHandler>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::post()
+    let refresh = warp::post()
+        .and(warp::path!("login" / "refresh"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(refresh);
+
+    let login_mfa = warp::post()
+        .and(warp::path!("login" / "mfa"))
+        .and(json_body())
+        .and(warp::header::optional::<String>("X-Forwarded-For"))
+        .and(with_handler(handler.clone()))
+        .and_then(login_mfa);
+
+    let login = warp::post()
         .and(warp::path("login"))
-        .and(warp::body::json())
+        .and(json_body())
+        .and(warp::header::optional::<String>("X-Forwarded-For"))
+        .and(with_handler(handler.clone()))
+        .and_then(login);
+
+    let logout = warp::post()
+        .and(warp::path("logout"))
+        .and(warp::header::<String>("Authorization"))
         .and(with_handler(handler))
-        .and_then(login)
+        .and_then(logout);
+
+    refresh.or(login_mfa).or(login).or(logout)
 }
@@ -0,0 +1,157 @@
+use crate::api::{
+    is_authorized, issue, json_body, optional_json_body, revoke_sessions, with_handler, ApiHandler,
+};
+use crate::auth::{token_digest, Claims};
+use crate::error::Error::{Forbidden, InvalidMfaCode, MfaAlreadyEnabled, UserDoesNotExist};
+use crate::error::Result as CoreResult;
+use crate::mfa::{check_code, generate_recovery_codes, generate_secret, otpauth_uri};
+use crate::model::user::{MfaCode, MfaEnabled, MfaEnrollment, User};
+use chrono::Utc;
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+/// Checks a code from the authenticator or a recovery code and consumes it, so
+/// neither can be replayed.
+pub(super) async fn use_mfa_code(handler: &ApiHandler, u: &User, code: &str) -> CoreResult<bool> {
+    Ok(match (&u.totp_secret, u.totp_enabled) {
+        (Some(secret), true) => match check_code(secret, code, u.totp_last_step)? {
+            Some(step) => handler.db.use_totp_step(u.id, step).await?,
+            None => {
+                handler
+                    .db
+                    .use_recovery_code(u.id, token_digest(code))
+                    .await?
+            }
+        },
+        _ => false,
+    })
+}
+
+/// Starts an enrollment with a fresh secret, replacing any unconfirmed one.
+async fn enroll(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:mfa", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    if u.totp_enabled {
+        Err(MfaAlreadyEnabled)?;
+    }
+
+    let secret = generate_secret();
+    let otpauth_uri = otpauth_uri(&secret, &u.username)?;
+    handler.db.set_totp_secret(id, secret.clone()).await?;
+
+    Ok(warp::reply::json(&MfaEnrollment {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+/// Enables the second factor with a first code from the authenticator. Other
+/// sessions are revoked and the caller gets credentials carrying the factor.
+async fn confirm(
+    id: Uuid,
+    code: MfaCode,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:mfa", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    if u.totp_enabled {
+        Err(MfaAlreadyEnabled)?;
+    }
+    let secret = u.totp_secret.ok_or(InvalidMfaCode)?;
+    let step = check_code(&secret, &code.code, None)?.ok_or(InvalidMfaCode)?;
+
+    let recovery_codes = generate_recovery_codes();
+    handler
+        .db
+        .enable_totp(
+            id,
+            step,
+            recovery_codes.iter().map(|e| token_digest(e)).collect(),
+        )
+        .await?;
+    revoke_sessions(&handler, id).await?;
+
+    let claims = Claims {
+        role: u.role,
+        id,
+        session: Utc::now(),
+        must_change_password: u.must_change_password,
+        mfa: true,
+    };
+    let t = handler.auth.build_token(&claims)?;
+
+    Ok(warp::reply::json(&MfaEnabled {
+        recovery_codes,
+        credentials: issue(&handler, t, &claims).await?,
+    }))
+}
+
+/// Removes the second factor, by its owner with a current code or by an admin
+/// when the authenticator and the recovery codes are lost.
+async fn disable(
+    id: Uuid,
+    code: Option<MfaCode>,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let reset = is_authorized(auth_token.clone(), &handler.auth, "users:mfa_reset", None)?;
+    if !reset && !is_authorized(auth_token, &handler.auth, "users:mfa", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+
+    // a stolen session alone must not be enough to remove the factor
+    if !reset {
+        handler.lockouts.check(&u.username, None)?;
+
+        let code = code.ok_or(InvalidMfaCode)?;
+        if !use_mfa_code(&handler, &u, &code.code).await? {
+            handler.lockouts.failure(&u.username, None);
+            Err(InvalidMfaCode)?;
+        }
+    }
+
+    handler.db.disable_totp(id).await?;
+    revoke_sessions(&handler, id).await?;
+
+    Ok(warp::reply())
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let enroll = warp::post()
+        .and(warp::path!("users" / Uuid / "mfa"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(enroll);
+
+    let confirm = warp::post()
+        .and(warp::path!("users" / Uuid / "mfa" / "confirm"))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(confirm);
+
+    let disable = warp::delete()
+        .and(warp::path!("users" / Uuid / "mfa"))
+        .and(optional_json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(disable);
+
+    enroll.or(confirm).or(disable)
+}
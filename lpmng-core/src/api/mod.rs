@@ -1,32 +1,61 @@
-use crate::auth::{check_admin, check_id, get_id};
+use crate::auth::{AuthHandler, Claims, Token};
 use crate::db::DbHandler;
 use crate::error::Error;
-use crate::error::Error::{AuthorizationHeaderMalformed, RouterError};
+use crate::error::Error::{AuthorizationHeaderMalformed, InvalidInput, RouterError};
 use crate::error::Result;
+use crate::lockout::LockoutHandler;
 use crate::mac::MacHandler;
-use biscuit_auth::PrivateKey;
+use crate::mailer::Mailer;
+use crate::model::device::DeviceQuota;
+use crate::model::login::Credentials;
+use crate::oidc::OidcHandler;
 use lpmng_mq::client::agent::AgentResponse;
+use serde::de::DeserializeOwned;
 use std::convert::Infallible;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tracing::{debug, error};
 use uuid::Uuid;
+use warp::hyper::body::Bytes;
 use warp::{Filter, Rejection, Reply};
 
+mod api_keys;
 mod devices;
+mod email;
+mod export;
+mod import;
+mod lockouts;
 mod login;
+mod mfa;
+mod oidc;
+mod privacy;
 mod users;
 
+pub use privacy::retention_job;
+
 pub struct ApiHandler {
     pub db: DbHandler,
-    pub auth_key: PrivateKey,
-    pub admin_key: String,
+    pub auth: AuthHandler,
+    pub break_glass_key: Option<String>,
     pub router: Mutex<lpmng_mq::client::Client>,
     pub mac_handler: MacHandler,
+    pub lockouts: LockoutHandler,
+    /// Limits password reset mails per address and per source ip, each request
+    /// counting as a failure.
+    pub reset_requests: LockoutHandler,
+    pub mailer: Box<dyn Mailer>,
+    /// Base url of the front, used to build the links sent by email.
+    pub public_url: String,
+    pub verify_token_ttl: chrono::Duration,
+    pub reset_token_ttl: chrono::Duration,
+    pub oidc: Option<OidcHandler>,
+    pub device_quota: DeviceQuota,
+    /// How many proxies in front of the server append to `X-Forwarded-For`.
+    pub proxy_hops: usize,
 }
 
-fn is_admin(auth_token: String, private_key: &PrivateKey) -> Result<bool> {
+fn bearer(auth_token: String) -> Result<String> {
     let mut split = auth_token.split(" ");
 
     if split.clone().count() != 2 {
@@ -37,21 +66,68 @@ fn is_admin(auth_token: String, private_key: &PrivateKey) -> Result<bool> {
         return Err(AuthorizationHeaderMalformed);
     }
 
-    check_admin(split.nth(1).unwrap().into(), private_key)
+    Ok(split.nth(1).unwrap().into())
 }
 
-fn is_user(id: Uuid, auth_token: String, private_key: &PrivateKey) -> Result<bool> {
-    let mut split = auth_token.split(" ");
+/// Checks the policies allow the bearer of `auth_token` to run `operation`,
+/// `owner` being the user owning the targeted resource if there is one.
+fn is_authorized(
+    auth_token: String,
+    auth: &AuthHandler,
+    operation: &str,
+    owner: Option<Uuid>,
+) -> Result<bool> {
+    auth.authorize(bearer(auth_token)?, operation, owner)
+}
 
-    if split.clone().count() != 2 {
-        return Err(AuthorizationHeaderMalformed);
-    }
+/// Records an issued token so it can be revoked later, then wraps it for the client.
+async fn issue(handler: &ApiHandler, token: Token, claims: &Claims) -> Result<Credentials> {
+    handler
+        .db
+        .insert_token(token.revocation_id, claims.id, token.expires_at.naive_utc())
+        .await?;
 
-    if split.clone().next().unwrap() != "Bearer" {
-        return Err(AuthorizationHeaderMalformed);
-    }
+    Ok(Credentials {
+        biscuit: token.biscuit,
+        role: claims.role,
+        user_id: claims.id,
+        expires_at: token.expires_at,
+        must_change_password: claims.must_change_password,
+    })
+}
+
+/// Revokes every session of a user, in the database and in the auth cache.
+async fn revoke_sessions(handler: &ApiHandler, user_id: Uuid) -> Result<()> {
+    let revoked = handler.db.revoke_user_tokens(user_id).await?;
+    handler.auth.revoke(revoked);
+    Ok(())
+}
+
+/// Revokes the api keys created by a user, for when the user loses the rights
+/// the keys were given.
+async fn revoke_api_keys(handler: &ApiHandler, user_id: Uuid) -> Result<()> {
+    let revoked = handler.db.revoke_user_api_keys(user_id).await?;
+    handler.auth.revoke(revoked);
+    Ok(())
+}
+
+impl ApiHandler {
+    /// Address of the client in a `X-Forwarded-For` header. Every proxy
+    /// appends the address it got the request from, so the entry `proxy_hops`
+    /// from the end is the one written by the outermost trusted proxy, the ones
+    /// before it being set by the client.
+    fn client_ip<'a>(&self, forwarded_for: &'a str) -> &'a str {
+        let entries = forwarded_for.split(',').collect::<Vec<_>>();
 
-    check_id(id, split.nth(1).unwrap().into(), private_key)
+        entries
+            .len()
+            .checked_sub(self.proxy_hops)
+            .and_then(|i| entries.get(i))
+            .or(entries.first())
+            .copied()
+            .unwrap_or_default()
+            .trim()
+    }
 }
 
 fn trace_router_response(res: AgentResponse) -> Result<()> {
@@ -64,6 +140,43 @@ fn trace_router_response(res: AgentResponse) -> Result<()> {
     }
 }
 
+/// Like `warp::body::json`, but validation failures name the offending field
+/// so clients can show the message next to it.
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract=(T,), Error=Rejection> + Clone {
+    warp::body::bytes().and_then(|body: Bytes| async move { parse_json(&body) })
+}
+
+/// Like `json_body`, but an empty body gives `None`.
+fn optional_json_body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract=(Option<T>,), Error=Rejection> + Clone {
+    warp::body::bytes().and_then(|body: Bytes| async move {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            Ok(None)
+        } else {
+            parse_json(&body).map(Some)
+        }
+    })
+}
+
+fn parse_json<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, Rejection> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(body);
+
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let message = e.inner().to_string();
+        // the position in the body is not helpful once the field is known
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_string(),
+            None => message,
+        };
+
+        warp::reject::custom(InvalidInput {
+            field: (field != ".").then_some(field),
+            message,
+        })
+    })
+}
+
 fn with_handler(
     handler: Arc<ApiHandler>,
 ) -> impl Filter<Extract=(Arc<ApiHandler>,), Error=Infallible> + Clone {
@@ -75,15 +188,25 @@ pub fn api_routes(
 ) -> impl Filter<Extract=impl Reply, Error=Rejection> + Clone {
     warp::path("api")
         .and(
-            devices::routes(handler.clone())
+            // nested /users/... and /login/... routes go before the users and
+            // login routes whose prefix match would consume the request body
+            mfa::routes(handler.clone())
+                .or(oidc::routes(handler.clone()))
+                .or(email::routes(handler.clone()))
+                .or(import::routes(handler.clone()))
+                .or(export::routes(handler.clone()))
+                .or(privacy::routes(handler.clone()))
+                .or(devices::routes(handler.clone()))
                 .or(users::routes(handler.clone()))
-                .or(login::routes(handler.clone())),
+                .or(login::routes(handler.clone()))
+                .or(lockouts::routes(handler.clone()))
+                .or(api_keys::routes(handler.clone())),
         )
         .recover(Error::handle_warp_rejection)
         .with(
             warp::cors()
                 .allow_any_origin()
-                .allow_methods(vec!["GET", "POST", "DELETE", "PATCH", "OPTIONS"])
+                .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"])
                 .allow_header("content-type")
                 .allow_header("authorization")
         )
@@ -92,19 +215,15 @@ pub fn api_routes(
             let ip = headers
                 .get("X-Forwarded-For")
                 .and_then(|e| e.to_str().ok())
-                .and_then(|e| {
-                    if e.contains(",") {
-                        e.split(",").next()
-                    } else {
-                        Some(e)
-                    }
-                });
+                .map(|e| handler.client_ip(e));
             let user_id = headers
                 .get("Authorization")
                 .and_then(|e| e.to_str().ok())
                 .and_then(|e| e.strip_prefix("Bearer "))
                 .and_then(|e| {
-                    get_id(e.to_string(), &handler.auth_key)
+                    handler
+                        .auth
+                        .get_id(e.to_string())
                         .map_err(|error| error!(?error))
                         .ok()
                 });
@@ -0,0 +1,184 @@
+use crate::api::{issue, json_body, revoke_api_keys, revoke_sessions, with_handler, ApiHandler};
+use crate::auth::{hash, random_string, Claims};
+use crate::error::Error::{
+    OidcAccountConflict, OidcDisabled, OidcError, OidcMissingClaim, UserDoesNotExist,
+};
+use crate::model::login::{MfaChallenge, OidcCallback};
+use crate::model::user::{Role, UserInputUnchecked};
+use crate::model::utils::{is_reserved_username, normalize_username, Username, ValidString};
+use crate::oidc::Identity;
+use chrono::Utc;
+use serde::de::value::{Error as ValueError, StringDeserializer};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::http::Uri;
+use warp::{Filter, Rejection, Reply};
+
+/// Sends the browser to the identity provider.
+async fn authorize(handler: Arc<ApiHandler>) -> Result<impl Reply, Rejection> {
+    let oidc = handler.oidc.as_ref().ok_or(OidcDisabled)?;
+
+    let url = oidc
+        .authorize_url()
+        .parse::<Uri>()
+        .map_err(|e| OidcError(e.to_string()))?;
+
+    Ok(warp::redirect::found(url))
+}
+
+/// Runs the checks of an input type on a claim, `None` when they fail.
+fn checked<T: DeserializeOwned>(claim: String) -> Option<T> {
+    T::deserialize(StringDeserializer::<ValueError>::new(claim)).ok()
+}
+
+/// Creates the account of someone logging in through the provider for the
+/// first time. The password is random, a local one can be set with the
+/// forgotten password flow.
+async fn create_account(handler: &ApiHandler, identity: &Identity) -> crate::error::Result<Uuid> {
+    let candidate = identity
+        .username
+        .clone()
+        .unwrap_or_else(|| identity.email.split('@').next().unwrap_or_default().into())
+        .replace(' ', "");
+    // same rules as a registration, a name breaking them is replaced
+    let mut username = match checked::<Username>(candidate) {
+        Some(username) => username.into(),
+        None => format!("user-{}", random_string(6)),
+    };
+    if is_reserved_username(&username) || handler.db.username_exists(username.clone()).await? {
+        username = format!("{username}-{}", random_string(4));
+    }
+    let username = normalize_username(&username);
+
+    let name = |claim: &'static str, value: &Option<String>| {
+        value
+            .clone()
+            .and_then(|e| checked::<ValidString>(e.trim().to_string()))
+            .map(Into::into)
+            .ok_or(OidcMissingClaim(claim))
+    };
+
+    let id = handler
+        .db
+        .insert_user(
+            UserInputUnchecked {
+                username,
+                firstname: name("given_name", &identity.firstname)?,
+                lastname: name("family_name", &identity.lastname)?,
+                email: identity.email.clone(),
+                password: hash(random_string(32)),
+                phone: identity.phone.clone().unwrap_or_default(),
+            },
+            identity.role.unwrap_or_default(),
+        )
+        .await?;
+
+    Ok(id)
+}
+
+/// Finds the account linked to the provider subject, otherwise links the
+/// account with the same verified email, otherwise creates one.
+async fn link_account(handler: &ApiHandler, identity: &Identity) -> crate::error::Result<Uuid> {
+    if let Some(id) = handler
+        .db
+        .get_user_id_by_oidc_subject(identity.subject.clone())
+        .await?
+    {
+        return Ok(id);
+    }
+
+    let id = match handler
+        .db
+        .get_user_id_by_email(identity.email.clone())
+        .await?
+    {
+        // an unverified address could be used to take over a local account
+        Some(_) if !identity.email_verified => return Err(OidcAccountConflict),
+        // nor is a provider trusted to hand over an account with rights
+        Some(id)
+            if handler
+                .db
+                .get_user(id)
+                .await?
+                .is_some_and(|u| u.role != Role::User) =>
+        {
+            return Err(OidcAccountConflict)
+        }
+        Some(id) => id,
+        None => create_account(handler, identity).await?,
+    };
+
+    handler
+        .db
+        .link_oidc_subject(id, identity.subject.clone())
+        .await?;
+    if identity.email_verified {
+        handler.db.verify_email(id, identity.email.clone()).await?;
+    }
+
+    Ok(id)
+}
+
+async fn callback(
+    callback: OidcCallback,
+    forwarded_for: Option<String>,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    let ip = forwarded_for
+        .as_deref()
+        .map(|e| handler.client_ip(e).to_string());
+    let oidc = handler.oidc.as_ref().ok_or(OidcDisabled)?;
+
+    let identity = oidc.exchange(callback.code, callback.state).await?;
+    let id = link_account(&handler, &identity).await?;
+    let mut u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+
+    // the provider is authoritative on roles when a role claim is configured
+    if let Some(role) = identity.role.filter(|role| *role != u.role) {
+        handler.db.update_role(id, role).await?;
+        revoke_sessions(&handler, id).await?;
+        revoke_api_keys(&handler, id).await?;
+        u.role = role;
+    }
+
+    if u.totp_enabled && !identity.mfa {
+        return Ok(warp::reply::json(&MfaChallenge {
+            mfa_token: handler.auth.build_mfa_token(id)?,
+        }));
+    }
+
+    handler
+        .db
+        .insert_audit("oidc_login", Some(id), ip, None)
+        .await?;
+
+    let claims = Claims {
+        role: u.role,
+        id,
+        session: Utc::now(),
+        must_change_password: false,
+        mfa: identity.mfa,
+    };
+    let t = handler.auth.build_token(&claims)?;
+
+    Ok(warp::reply::json(&issue(&handler, t, &claims).await?))
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let authorize = warp::get()
+        .and(warp::path!("login" / "oidc"))
+        .and(with_handler(handler.clone()))
+        .and_then(authorize);
+
+    let callback = warp::post()
+        .and(warp::path!("login" / "oidc" / "callback"))
+        .and(json_body())
+        .and(warp::header::optional::<String>("X-Forwarded-For"))
+        .and(with_handler(handler))
+        .and_then(callback);
+
+    authorize.or(callback)
+}
@@ -0,0 +1,157 @@
+use crate::api::users::erase_user;
+use crate::api::{
+    is_authorized, json_body, revoke_api_keys, revoke_sessions, trace_router_response,
+    with_handler, ApiHandler,
+};
+use crate::error::Error::{Forbidden, UserDoesNotExist};
+use crate::model::privacy::{PersonalData, Retention};
+use futures::FutureExt;
+use lpmng_mq::client::agent::RouterRequest;
+use std::sync::Arc;
+use tracing::{error, info};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+/// Hands users everything stored about them.
+async fn get_personal_data(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:data", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+
+    Ok(warp::reply::json(&PersonalData {
+        user: u.into_view(),
+        devices: handler.db.get_devices_by_user_id(id).await?,
+        sessions: handler.db.get_sessions_by_user_id(id).await?,
+        history: handler.db.get_audit_by_user_id(id).await?,
+    }))
+}
+
+/// Lets users delete their own account.
+async fn delete_account(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:delete", Some(id))? {
+        Err(Forbidden)?;
+    }
+
+    erase_user(&handler, id).await?;
+    handler
+        .db
+        .insert_audit("account_deleted", Some(id), None, None)
+        .await?;
+
+    Ok(warp::reply())
+}
+
+async fn get_retention(
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "retention:manage", None)? {
+        Err(Forbidden)?;
+    }
+
+    Ok(warp::reply::json(&handler.db.get_retention().await?))
+}
+
+async fn set_retention(
+    retention: Retention,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "retention:manage", None)? {
+        Err(Forbidden)?;
+    }
+
+    handler.db.set_retention(retention).await?;
+    Ok(warp::reply())
+}
+
+/// Removes the devices of a user having internet from the router, their rows
+/// are left to the caller.
+async fn disconnect_devices(handler: &ApiHandler, user_id: Uuid) -> crate::error::Result<()> {
+    for device in handler.db.get_devices_by_user_id(user_id).await? {
+        if device.internet {
+            handler
+                .router
+                .lock()
+                .await
+                .send(RouterRequest {
+                    action: "remove".to_string(),
+                    body: device.mac,
+                })
+                .map(trace_router_response)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn anonymize_due_users(handler: &ApiHandler) -> crate::error::Result<usize> {
+    let ids = handler.db.get_users_to_anonymize().await?;
+
+    for id in &ids {
+        disconnect_devices(handler, *id).await?;
+        revoke_sessions(handler, *id).await?;
+        revoke_api_keys(handler, *id).await?;
+        handler.db.anonymize_user(*id).await?;
+    }
+
+    Ok(ids.len())
+}
+
+/// Anonymizes the participants whose retention period is over, checking
+/// every `interval`.
+pub async fn retention_job(handler: Arc<ApiHandler>, interval: std::time::Duration) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        match anonymize_due_users(&handler).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "participants anonymized"),
+            Err(error) => error!(?error, "failed to anonymize participants"),
+        }
+    }
+}
+
+pub(super) fn routes(
+    handler: Arc<ApiHandler>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let data = warp::get()
+        .and(warp::path!("users" / Uuid / "data"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(get_personal_data);
+
+    let delete = warp::delete()
+        .and(warp::path!("users" / Uuid))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(delete_account);
+
+    let get_retention = warp::get()
+        .and(warp::path!("retention"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(get_retention);
+
+    let set_retention = warp::put()
+        .and(warp::path!("retention"))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler))
+        .and_then(set_retention);
+
+    data.or(delete).or(get_retention).or(set_retention)
+}
@@ -1,8 +1,15 @@
-use crate::api::{is_admin, is_user, trace_router_response, with_handler, ApiHandler};
-use crate::auth::hash;
-use crate::error::Error::{Forbidden, InvalidCredential, UserDoesNotExist};
+use crate::api::email::send_verification;
+use crate::api::{
+    bearer, is_authorized, issue, json_body, revoke_api_keys, revoke_sessions,
+    trace_router_response, with_handler, ApiHandler,
+};
+use crate::auth::{check_hash, generate_password, hash, Claims};
+use crate::error::Error::{Forbidden, InvalidCredential, InvalidInput, UserDoesNotExist};
 use crate::model::device::Device;
-use crate::model::user::{User, UserInput, UserPatch};
+use crate::model::user::{
+    PasswordChange, Role, TemporaryPassword, User, UserInput, UserPage, UserPatch, UserQuery,
+};
+use crate::model::utils::is_reserved_username;
 use chrono::Utc;
 use futures::FutureExt;
 use lpmng_mq::client::agent::RouterRequest;
@@ -11,18 +18,20 @@ use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 
 async fn get_users(
+    query: UserQuery,
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if !is_admin(auth_token, &handler.auth_key)? {
+    if !is_authorized(auth_token, &handler.auth, "users:list", None)? {
         Err(Forbidden)?;
     }
 
-    let res = handler.db.get_users().await?;
+    let (total, res) = handler.db.get_users(&query).await?;
 
-    Ok(warp::reply::json(
-        &res.into_iter().map(User::into_view).collect::<Vec<_>>(),
-    ))
+    Ok(warp::reply::json(&UserPage {
+        total,
+        users: res.into_iter().map(User::into_view).collect(),
+    }))
 }
 
 async fn get_user(
@@ -30,8 +39,7 @@ async fn get_user(
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let auth_key = &handler.auth_key;
-    if !is_admin(auth_token.clone(), auth_key)? && !is_user(id, auth_token, auth_key)? {
+    if !is_authorized(auth_token, &handler.auth, "users:read", Some(id))? {
         Err(Forbidden)?;
     }
 
@@ -47,13 +55,19 @@ async fn create_user(
     user: UserInput,
     handler: Arc<ApiHandler>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if *user.username == "admin" {
-        Err(InvalidCredential)?;
+    if is_reserved_username(&user.username) {
+        Err(InvalidInput {
+            field: Some("username".into()),
+            message: "username reserved".into(),
+        })?;
     }
 
     let mut user = user.into_unchecked();
     user.password = hash(user.password);
-    handler.db.insert_user(user).await?;
+    let email = user.email.clone();
+    let id = handler.db.insert_user(user, Role::User).await?;
+
+    send_verification(&handler, id, email).await;
 
     Ok(warp::reply())
 }
@@ -63,7 +77,26 @@ async fn patch_user(
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if !is_admin(auth_token, &handler.auth_key)? {
+    let auth = &handler.auth;
+    // owners can edit their contact details, the account itself stays an admin matter
+    let writes_profile = user.firstname.is_some()
+        || user.lastname.is_some()
+        || user.email.is_some()
+        || user.phone.is_some();
+    let writes_account = user.username.is_some() || user.role.is_some();
+    // toggling is_allowed alone is a separate right, an empty patch is a write
+    let writes_account = writes_account || (!writes_profile && user.is_allowed.is_none());
+    let can_write = is_authorized(auth_token.clone(), auth, "users:write", None)?;
+    if writes_account && !can_write {
+        Err(Forbidden)?;
+    }
+    if writes_profile
+        && !can_write
+        && !is_authorized(auth_token.clone(), auth, "users:profile", Some(user.id))?
+    {
+        Err(Forbidden)?;
+    }
+    if user.is_allowed.is_some() && !is_authorized(auth_token, auth, "users:allow", None)? {
         Err(Forbidden)?;
     }
 
@@ -72,6 +105,18 @@ async fn patch_user(
         .get_user(user.id)
         .await?
         .ok_or(UserDoesNotExist)?;
+    if user
+        .username
+        .as_ref()
+        .is_some_and(|e| **e != u.username && is_reserved_username(e))
+    {
+        Err(InvalidInput {
+            field: Some("username".into()),
+            message: "username reserved".into(),
+        })?;
+    }
+    let role_changed = user.role.is_some_and(|role| role != u.role);
+    let email_changed = user.email.as_ref().is_some_and(|email| **email != u.email);
     let new = User {
         id: user.id,
         username: user.username.map(|e| e.to_string()).unwrap_or(u.username),
@@ -80,10 +125,24 @@ async fn patch_user(
         email: user.email.map(|e| e.to_string()).unwrap_or(u.email),
         password: u.password,
         phone: user.phone.map(|e| e.to_string()).unwrap_or(u.phone),
-        role: user.role.map(|e| e.to_string()).unwrap_or(u.role),
+        role: user.role.unwrap_or(u.role),
         is_allowed: user.is_allowed.unwrap_or(u.is_allowed),
+        must_change_password: u.must_change_password,
+        email_verified: u.email_verified && !email_changed,
+        totp_secret: u.totp_secret,
+        totp_enabled: u.totp_enabled,
+        totp_last_step: u.totp_last_step,
+        max_devices: u.max_devices,
     };
+    let email = new.email.clone();
     handler.db.update_user(new).await?;
+    if role_changed {
+        revoke_sessions(&handler, user.id).await?;
+        revoke_api_keys(&handler, user.id).await?;
+    }
+    if email_changed {
+        send_verification(&handler, user.id, email).await;
+    }
     if user.is_allowed == Some(false) {
         let devices = handler.db.get_devices_by_user_id(user.id).await?;
         for device in devices {
@@ -101,11 +160,9 @@ async fn patch_user(
                 handler
                     .db
                     .update_device(Device {
-                        id: device.id,
-                        mac: device.mac,
-                        user_id: device.user_id,
                         internet: false,
                         date_time: Utc::now().naive_utc(),
+                        ..device
                     })
                     .await?;
             }
@@ -114,7 +171,8 @@ async fn patch_user(
     if user.is_allowed == Some(true) {
         let devices = handler.db.get_devices_by_user_id(user.id).await?;
         for device in devices {
-            if !device.internet {
+            // devices cut one by one stay off
+            if !device.internet && !device.disabled {
                 handler
                     .router
                     .lock()
@@ -128,11 +186,9 @@ async fn patch_user(
                 handler
                     .db
                     .update_device(Device {
-                        id: device.id,
-                        mac: device.mac,
-                        user_id: device.user_id,
                         internet: true,
                         date_time: Utc::now().naive_utc(),
+                        ..device
                     })
                     .await?;
             }
@@ -146,43 +202,136 @@ pub async fn delete_user(
     auth_token: String,
     handler: Arc<ApiHandler>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    if !is_admin(auth_token, &handler.auth_key)? {
+    if !is_authorized(auth_token, &handler.auth, "users:delete", None)? {
         Err(Forbidden)?;
     }
 
-    let u = handler
-        .db
-        .get_user(user.id)
-        .await?
-        .ok_or(UserDoesNotExist)?;
-    if u.is_allowed {
-        let devices = handler.db.get_devices_by_user_id(user.id).await?;
-        for device in devices {
-            if device.internet {
-                handler
-                    .router
-                    .lock()
-                    .await
-                    .send(RouterRequest {
-                        action: "remove".to_string(),
-                        body: device.mac.clone(),
-                    })
-                    .map(trace_router_response)
-                    .await?;
-            }
-            handler.db.delete_device(device.id).await?;
+    erase_user(&handler, user.id).await?;
+    Ok(warp::reply())
+}
+
+/// Takes the devices of a user off the router then deletes the account.
+pub(super) async fn erase_user(handler: &ApiHandler, id: Uuid) -> crate::error::Result<()> {
+    handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+
+    for device in handler.db.get_devices_by_user_id(id).await? {
+        if device.internet {
+            handler
+                .router
+                .lock()
+                .await
+                .send(RouterRequest {
+                    action: "remove".to_string(),
+                    body: device.mac.clone(),
+                })
+                .map(trace_router_response)
+                .await?;
         }
+        handler.db.delete_device(device.id).await?;
+    }
+
+    revoke_sessions(handler, id).await?;
+    revoke_api_keys(handler, id).await?;
+    handler.db.delete_user(id).await
+}
+
+async fn delete_sessions(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_authorized(auth_token, &handler.auth, "users:sessions", Some(id))? {
+        Err(Forbidden)?;
     }
 
-    handler.db.delete_user(user.id).await?;
+    revoke_sessions(&handler, id).await?;
     Ok(warp::reply())
 }
 
+/// Lets users replace their password, the other sessions are revoked and new
+/// credentials are returned since the current token may be restricted.
+async fn change_password(
+    id: Uuid,
+    change: PasswordChange,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_authorized(
+        auth_token.clone(),
+        &handler.auth,
+        "users:password",
+        Some(id),
+    )? {
+        Err(Forbidden)?;
+    }
+    // the second factor of the current session still holds for the new one
+    let mfa = handler.auth.claims(bearer(auth_token)?)?.mfa;
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    if !check_hash(change.old_password.to_string(), u.password) {
+        Err(InvalidCredential)?;
+    }
+
+    handler
+        .db
+        .update_password(id, hash(change.new_password.to_string()), false)
+        .await?;
+    revoke_sessions(&handler, id).await?;
+
+    let claims = Claims {
+        role: u.role,
+        id,
+        session: Utc::now(),
+        must_change_password: false,
+        mfa,
+    };
+    let t = handler.auth.build_token(&claims)?;
+
+    Ok(warp::reply::json(&issue(&handler, t, &claims).await?))
+}
+
+/// Replaces a forgotten password by a temporary one the user must change on next login.
+async fn reset_password(
+    id: Uuid,
+    auth_token: String,
+    handler: Arc<ApiHandler>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !is_authorized(
+        auth_token.clone(),
+        &handler.auth,
+        "users:password_reset",
+        None,
+    )? {
+        Err(Forbidden)?;
+    }
+    let (_, role) = handler
+        .auth
+        .caller(bearer(auth_token)?, "users:password_reset")
+        .map_err(|_| Forbidden)?;
+
+    let u = handler.db.get_user(id).await?.ok_or(UserDoesNotExist)?;
+    // the temporary password is handed to the caller, who must not get into
+    // accounts with as many rights as theirs unless they are an admin
+    if role != Role::Admin && u.role >= role {
+        Err(Forbidden)?;
+    }
+
+    let password = generate_password();
+    handler
+        .db
+        .update_password(id, hash(password.clone()), true)
+        .await?;
+    revoke_sessions(&handler, id).await?;
+
+    Ok(warp::reply::json(&TemporaryPassword { password }))
+}
+
 pub(super) fn routes(
     handler: Arc<ApiHandler>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let list = warp::get()
-        .and(warp::path("users"))
+        .and(warp::path!("users"))
+        .and(warp::query::<UserQuery>())
         .and(warp::header::<String>("Authorization"))
         .and(with_handler(handler.clone()))
         .and_then(get_users);
@@ -195,24 +344,49 @@ pub(super) fn routes(
         .and_then(get_user);
 
     let post = warp::post()
-        .and(warp::path("users"))
-        .and(warp::body::json())
+        .and(warp::path!("users"))
+        .and(json_body())
         .and(with_handler(handler.clone()))
         .and_then(create_user);
 
     let patch = warp::patch()
         .and(warp::path("users"))
-        .and(warp::body::json())
+        .and(json_body())
         .and(warp::header::<String>("Authorization"))
         .and(with_handler(handler.clone()))
         .and_then(patch_user);
 
+    let change_password = warp::post()
+        .and(warp::path!("users" / Uuid / "password"))
+        .and(json_body())
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(change_password);
+
+    let reset_password = warp::post()
+        .and(warp::path!("users" / Uuid / "password" / "reset"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(reset_password);
+
+    let delete_sessions = warp::delete()
+        .and(warp::path!("users" / Uuid / "sessions"))
+        .and(warp::header::<String>("Authorization"))
+        .and(with_handler(handler.clone()))
+        .and_then(delete_sessions);
+
     let delete = warp::delete()
         .and(warp::path("users"))
-        .and(warp::body::json())
+        .and(json_body())
         .and(warp::header::<String>("Authorization"))
         .and(with_handler(handler))
         .and_then(delete_user);
 
-    get.or(list).or(post).or(patch).or(delete)
+    get.or(list)
+        .or(change_password)
+        .or(reset_password)
+        .or(post)
+        .or(patch)
+        .or(delete_sessions)
+        .or(delete)
 }
@@ -1,48 +1,517 @@
-use crate::error::Error::BiscuitMalformed;
+use crate::error::Error::{
+    BiscuitMalformed, InvalidToken, KeyRingFull, KeyRingMalformed, SessionExpired, SessionRevoked,
+};
 use crate::error::Result;
-use biscuit_auth::{Biscuit, KeyPair, PrivateKey};
+use crate::model::user::Role;
+use biscuit_auth::builder::BlockBuilder;
+use biscuit_auth::error::{FailedCheck, Format, Logic};
+use biscuit_auth::{Authorizer, AuthorizerLimits, Biscuit, KeyPair, PrivateKey, PublicKey};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
 use password_auth::{generate_hash, verify_password};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::SystemTime;
 use uuid::Uuid;
 
-pub fn build_token(role: String, id: Uuid, private_key: &PrivateKey) -> Result<String> {
-    let root = KeyPair::from(private_key);
+/// Root keys used to sign and verify biscuits, indexed by their `root_key_id`.
+///
+/// Tokens are always signed with the newest key, older keys are only kept to
+/// verify tokens issued before a rotation until they are retired.
+pub struct KeyRing {
+    keys: BTreeMap<u32, KeyPair>,
+}
+
+impl KeyRing {
+    /// Reads the key file at `path`, creating it with a fresh key on first run.
+    pub fn load_or_create(path: &str) -> Result<Self> {
+        if Path::new(path).exists() {
+            Self::parse(&std::fs::read_to_string(path)?)
+        } else {
+            let keys = Self::generate();
+            keys.save(path)?;
+            Ok(keys)
+        }
+    }
+
+    pub fn generate() -> Self {
+        Self {
+            keys: BTreeMap::from([(0, KeyPair::new())]),
+        }
+    }
+
+    /// Parses `<root_key_id> <hex private key>` lines, `#` starts a comment.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut keys = BTreeMap::new();
+
+        for line in input.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, key) = line.split_once(' ').ok_or(KeyRingMalformed)?;
+            let id = id.parse::<u32>().map_err(|_| KeyRingMalformed)?;
+            let key = PrivateKey::from_bytes_hex(key.trim()).map_err(|_| KeyRingMalformed)?;
+
+            keys.insert(id, KeyPair::from(&key));
+        }
+
+        if keys.is_empty() {
+            Err(KeyRingMalformed)
+        } else {
+            Ok(Self { keys })
+        }
+    }
 
-    let mut builder = Biscuit::builder();
+    pub fn save(&self, path: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
 
-    builder.add_fact(format!("role(\"{}\")", role).as_str())?;
+        writeln!(
+            file,
+            "# lpmng biscuit root keys: <root_key_id> <private key>"
+        )?;
+        for (id, key) in &self.keys {
+            writeln!(file, "{id} {}", key.private().to_bytes_hex())?;
+        }
 
-    builder.add_fact(format!("id(\"{}\")", id).as_str())?;
+        Ok(())
+    }
 
-    let biscuit = builder.build(&root)?;
+    pub fn ids(&self) -> Vec<u32> {
+        self.keys.keys().copied().collect()
+    }
 
-    biscuit.to_base64().map_err(Into::into)
+    /// Adds a new signing key and returns its id.
+    pub fn rotate(&mut self) -> Result<u32> {
+        let id = self.newest().0.checked_add(1).ok_or(KeyRingFull)?;
+        self.keys.insert(id, KeyPair::new());
+        Ok(id)
+    }
+
+    /// Removes an old key, tokens signed with it will no longer be accepted.
+    /// The newest key cannot be retired since it is the one signing tokens.
+    pub fn retire(&mut self, id: u32) -> bool {
+        if id == self.newest().0 {
+            return false;
+        }
+
+        self.keys.remove(&id).is_some()
+    }
+
+    fn newest(&self) -> (u32, &KeyPair) {
+        self.keys
+            .last_key_value()
+            .map(|(id, key)| (*id, key))
+            .expect("key ring is never empty")
+    }
+
+    fn public(&self, root_key_id: Option<u32>) -> core::result::Result<PublicKey, Format> {
+        root_key_id
+            .and_then(|id| self.keys.get(&id))
+            .map(KeyPair::public)
+            .ok_or(Format::UnknownPublicKey)
+    }
+
+    /// Checks the signature of a token, tokens that do not decode or that were
+    /// signed by an unknown key are refused as invalid rather than failing.
+    fn verify(&self, auth_token: String) -> Result<Biscuit> {
+        Biscuit::from_base64(auth_token, |id| self.public(id)).map_err(|e| match e {
+            biscuit_auth::error::Token::Format(_) | biscuit_auth::error::Token::Base64(_) => {
+                InvalidToken
+            }
+            e => e.into(),
+        })
+    }
 }
 
-pub fn check_admin(auth_token: String, private_key: &PrivateKey) -> Result<bool> {
-    let t = Biscuit::from_base64(auth_token, |_| Ok(private_key.public()))?;
+fn datalog_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
 
-    let mut auth = t.authorizer()?;
-    auth.add_code("allow if role(\"admin\")")?;
+fn revocation_hex(id: &[u8]) -> String {
+    id.iter().map(|e| format!("{e:02x}")).collect()
+}
 
-    Ok(auth.authorize().is_ok())
+/// What a token says about its bearer.
+#[derive(Clone)]
+pub struct Claims {
+    pub role: Role,
+    pub id: Uuid,
+    /// Time of the login that started the session, kept when refreshing.
+    pub session: DateTime<Utc>,
+    /// Set for temporary passwords, the policies then only allow changing it.
+    pub must_change_password: bool,
+    /// Whether the login went through a second factor.
+    pub mfa: bool,
 }
 
-pub fn check_id(id: Uuid, auth_token: String, private_key: &PrivateKey) -> Result<bool> {
-    let t = Biscuit::from_base64(auth_token, |_| Ok(private_key.public()))?;
+pub struct Token {
+    pub biscuit: String,
+    /// Revocation identifier of the authority block, which identifies the session.
+    pub revocation_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct AuthHandler {
+    keys: KeyRing,
+    token_ttl: Duration,
+    session_ttl: Duration,
+    revoked: RwLock<HashSet<String>>,
+    policies: String,
+}
+
+impl AuthHandler {
+    pub fn new(
+        keys: KeyRing,
+        token_ttl: Duration,
+        session_ttl: Duration,
+        revoked: Vec<String>,
+        policies: String,
+    ) -> Result<Self> {
+        // parse the policies once so a broken file is caught at startup
+        Authorizer::new().add_code(&policies)?;
+
+        Ok(Self {
+            keys,
+            token_ttl,
+            session_ttl,
+            revoked: RwLock::new(revoked.into_iter().collect()),
+            policies,
+        })
+    }
+
+    /// Adds revocation identifiers to the in-memory cache, the caller is
+    /// responsible for persisting them.
+    pub fn revoke(&self, revocation_ids: Vec<String>) {
+        self.revoked
+            .write()
+            .expect("revocation cache poisoned")
+            .extend(revocation_ids);
+    }
+
+    fn is_revoked(&self, token: &Biscuit) -> bool {
+        let revoked = self.revoked.read().expect("revocation cache poisoned");
+
+        token
+            .revocation_identifiers()
+            .iter()
+            .any(|id| revoked.contains(&revocation_hex(id)))
+    }
+
+    /// Verifies the token, rejects it if it was revoked and returns an
+    /// authorizer that enforces its expiry.
+    fn authorizer(&self, auth_token: String) -> Result<Authorizer> {
+        let token = self.keys.verify(auth_token)?;
+
+        if self.is_revoked(&token) {
+            return Err(SessionRevoked);
+        }
+
+        let mut auth = token.authorizer()?;
+        auth.set_time();
+        // the default 1ms is not always enough for the policy file on a busy
+        // server, running out of time denies the request
+        auth.set_limits(AuthorizerLimits {
+            max_time: std::time::Duration::from_millis(50),
+            ..Default::default()
+        });
+        Ok(auth)
+    }
+
+    /// Builds a token valid for `token_ttl`.
+    pub fn build_token(&self, claims: &Claims) -> Result<Token> {
+        let (root_key_id, root) = self.keys.newest();
+        let expires_at = Utc::now() + self.token_ttl;
+
+        let mut builder = Biscuit::builder();
+        builder.set_root_key_id(root_key_id);
+
+        builder.add_fact(format!("role(\"{}\")", claims.role).as_str())?;
+
+        builder.add_fact(format!("id(\"{}\")", claims.id).as_str())?;
+
+        builder.add_fact(format!("session({})", datalog_date(claims.session)).as_str())?;
+
+        if claims.must_change_password {
+            builder.add_fact("must_change_password(true)")?;
+        }
+
+        // always set so policies can match tokens without a second factor
+        builder.add_fact(format!("mfa({})", claims.mfa).as_str())?;
+
+        builder.add_check(
+            format!("check if time($time), $time < {}", datalog_date(expires_at)).as_str(),
+        )?;
+
+        let biscuit = builder.build(root)?;
+
+        let revocation_id = biscuit
+            .revocation_identifiers()
+            .first()
+            .map(|id| revocation_hex(id))
+            .ok_or(BiscuitMalformed)?;
+
+        Ok(Token {
+            biscuit: biscuit.to_base64()?,
+            revocation_id,
+            expires_at,
+        })
+    }
+
+    /// Reads the claims of a valid token.
+    pub fn claims(&self, auth_token: String) -> Result<Claims> {
+        let mut auth = self.authorizer(auth_token)?;
+        auth.add_code("allow if id($id)")?;
+
+        if auth.authorize().is_err() {
+            return Err(SessionExpired);
+        }
+
+        let role: Vec<(String,)> = auth.query("data($role) <- role($role)")?;
+        let id: Vec<(String,)> = auth.query("data($id) <- id($id)")?;
+        let session: Vec<(SystemTime,)> = auth.query("data($session) <- session($session)")?;
+        let must_change_password: Vec<(bool,)> =
+            auth.query("data($must) <- must_change_password($must)")?;
+        let mfa: Vec<(bool,)> = auth.query("data($mfa) <- mfa($mfa)")?;
+
+        Ok(Claims {
+            role: role
+                .first()
+                .and_then(|e| e.0.parse().ok())
+                .ok_or(BiscuitMalformed)?,
+            id: id
+                .first()
+                .and_then(|e| Uuid::parse_str(&e.0).ok())
+                .ok_or(BiscuitMalformed)?,
+            session: session.first().map(|e| e.0).ok_or(BiscuitMalformed)?.into(),
+            must_change_password: must_change_password.first().is_some_and(|e| e.0),
+            mfa: mfa.first().is_some_and(|e| e.0),
+        })
+    }
+
+    /// Trades a still valid token for a fresh one, as long as the session it
+    /// belongs to is not older than `session_ttl`.
+    pub fn refresh_token(&self, auth_token: String) -> Result<(Token, Claims)> {
+        let claims = self.claims(auth_token)?;
+
+        if claims.session + self.session_ttl < Utc::now() {
+            return Err(SessionExpired);
+        }
+
+        let token = self.build_token(&claims)?;
+
+        Ok((token, claims))
+    }
+
+    /// Builds a key for scripts. It carries the claims of its creator without a
+    /// session, so it cannot be refreshed, and an attenuation block restricting
+    /// it to `operations`. Returns the key and its revocation identifier.
+    pub fn build_api_key(
+        &self,
+        claims: &Claims,
+        key_id: Uuid,
+        operations: &[String],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(String, String)> {
+        let (root_key_id, root) = self.keys.newest();
+
+        let mut builder = Biscuit::builder();
+        builder.set_root_key_id(root_key_id);
+
+        builder.add_fact(format!("role(\"{}\")", claims.role).as_str())?;
+
+        builder.add_fact(format!("id(\"{}\")", claims.id).as_str())?;
+
+        builder.add_fact(format!("mfa({})", claims.mfa).as_str())?;
+
+        builder.add_fact(format!("api_key(\"{key_id}\")").as_str())?;
+
+        if let Some(expires_at) = expires_at {
+            builder.add_check(
+                format!("check if time($time), $time < {}", datalog_date(expires_at)).as_str(),
+            )?;
+        }
+
+        let biscuit = builder.build(root)?;
+
+        let revocation_id = biscuit
+            .revocation_identifiers()
+            .first()
+            .map(|id| revocation_hex(id))
+            .ok_or(BiscuitMalformed)?;
+
+        let operations = operations
+            .iter()
+            .map(|op| format!("\"{op}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut block = BlockBuilder::new();
+        block
+            .add_check(format!("check if operation($op), [{operations}].contains($op)").as_str())?;
+
+        Ok((biscuit.append(block)?.to_base64()?, revocation_id))
+    }
+
+    /// Builds the short lived token proving the password step of a login
+    /// needing a second factor. It grants nothing else.
+    pub fn build_mfa_token(&self, id: Uuid) -> Result<String> {
+        let (root_key_id, root) = self.keys.newest();
+
+        let mut builder = Biscuit::builder();
+        builder.set_root_key_id(root_key_id);
+
+        builder.add_fact(format!("mfa_pending(\"{id}\")").as_str())?;
+
+        builder.add_check(
+            format!(
+                "check if time($time), $time < {}",
+                datalog_date(Utc::now() + Duration::minutes(5))
+            )
+            .as_str(),
+        )?;
+
+        Ok(builder.build(root)?.to_base64()?)
+    }
 
-    let mut auth = t.authorizer()?;
-    auth.add_code(format!("allow if id(\"{id}\")"))?;
+    /// Returns the user a pending second factor token was issued for.
+    pub fn check_mfa_token(&self, mfa_token: String) -> Result<Uuid> {
+        let mut auth = self.authorizer(mfa_token)?;
+        auth.add_code("allow if mfa_pending($id)")?;
 
-    Ok(auth.authorize().is_ok())
+        if auth.authorize().is_err() {
+            return Err(SessionExpired);
+        }
+
+        let id: Vec<(String,)> = auth.query("data($id) <- mfa_pending($id)")?;
+
+        id.first()
+            .and_then(|e| Uuid::parse_str(&e.0).ok())
+            .ok_or(BiscuitMalformed)
+    }
+
+    /// Returns the revocation identifier of the session a valid token belongs to.
+    pub fn revocation_id(&self, auth_token: String) -> Result<String> {
+        let token = self.keys.verify(auth_token)?;
+
+        token
+            .revocation_identifiers()
+            .first()
+            .map(|id| revocation_hex(id))
+            .ok_or(BiscuitMalformed)
+    }
+
+    /// Checks the token against the policies for `operation`, `owner` being
+    /// the user owning the targeted resource if there is one.
+    pub fn authorize(
+        &self,
+        auth_token: String,
+        operation: &str,
+        owner: Option<Uuid>,
+    ) -> Result<bool> {
+        let mut auth = self.authorizer(auth_token)?;
+
+        auth.add_fact(format!("operation(\"{operation}\")").as_str())?;
+        if let Some(owner) = owner {
+            auth.add_fact(format!("owner(\"{owner}\")").as_str())?;
+        }
+        auth.add_code(&self.policies)?;
+
+        match auth.authorize() {
+            Ok(_) => Ok(true),
+            Err(error) if is_expired(&error) => Err(SessionExpired),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Reads the id and role of a token used for `operation`. Unlike
+    /// `claims`, it accepts api keys, which only hold for the operations they
+    /// were restricted to.
+    pub fn caller(&self, auth_token: String, operation: &str) -> Result<(Uuid, Role)> {
+        let mut auth = self.authorizer(auth_token)?;
+        auth.add_fact(format!("operation(\"{operation}\")").as_str())?;
+        auth.add_code("allow if id($id)")?;
+
+        if auth.authorize().is_err() {
+            return Err(SessionExpired);
+        }
+
+        let role: Vec<(String,)> = auth.query("data($role) <- role($role)")?;
+        let id: Vec<(String,)> = auth.query("data($id) <- id($id)")?;
+
+        Ok((
+            id.first()
+                .and_then(|e| Uuid::parse_str(&e.0).ok())
+                .ok_or(BiscuitMalformed)?,
+            role.first()
+                .and_then(|e| e.0.parse().ok())
+                .ok_or(BiscuitMalformed)?,
+        ))
+    }
+
+    pub fn get_id(&self, auth_token: String) -> Result<String> {
+        let mut auth = self.authorizer(auth_token)?;
+        auth.add_code("allow if id($id)")?;
+
+        if auth.authorize().is_err() {
+            return Err(SessionExpired);
+        }
+
+        let res: Vec<(String,)> = auth.query("data($id) <- id($id)")?;
+
+        res.first().map(|e| e.0.clone()).ok_or(BiscuitMalformed)
+    }
 }
 
-pub fn get_id(auth_token: String, private_key: &PrivateKey) -> Result<String> {
-    let t = Biscuit::from_base64(auth_token, |_| Ok(private_key.public()))?;
+/// Whether authorization failed on the expiry check of a token rather than on
+/// the policies, so clients know to refresh it.
+fn is_expired(error: &biscuit_auth::error::Token) -> bool {
+    match error {
+        biscuit_auth::error::Token::FailedLogic(Logic::Unauthorized { checks, .. })
+        | biscuit_auth::error::Token::FailedLogic(Logic::NoMatchingPolicy { checks }) => {
+            checks.iter().any(|e| match e {
+                FailedCheck::Block(check) => check.rule.contains("time($time)"),
+                FailedCheck::Authorizer(_) => false,
+            })
+        }
+        _ => false,
+    }
+}
 
-    let mut auth = t.authorizer()?;
-    let res: Vec<(String,)> = auth.query("data($id) <- id($id)")?;
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Random password handed out by admins, to be replaced on next login.
+pub fn generate_password() -> String {
+    random_string(16)
+}
+
+/// Single use secret sent by email, only its digest is stored.
+pub fn generate_email_token() -> String {
+    random_string(48)
+}
 
-    res.first().map(|e| e.0.clone()).ok_or(BiscuitMalformed)
+/// Email tokens are random enough to be stored with a plain digest instead of a slow hash.
+pub fn token_digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|e| format!("{e:02x}"))
+        .collect()
 }
 
 pub fn hash(input: String) -> String {
@@ -52,3 +521,278 @@ pub fn hash(input: String) -> String {
 pub fn check_hash(input: String, h: String) -> bool {
     verify_password(input, h.as_str()).is_ok()
 }
+
+/// Whether a stored hash was made with another algorithm or weaker parameters
+/// than the ones `hash` currently uses.
+pub fn is_hash_obsolete(h: &str) -> bool {
+    password_auth::is_hash_obsolete(h).unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> AuthHandler {
+        handler_with(KeyRing::generate())
+    }
+
+    fn handler_with(keys: KeyRing) -> AuthHandler {
+        AuthHandler::new(
+            keys,
+            Duration::minutes(5),
+            Duration::hours(1),
+            vec![],
+            include_str!("../policies.dl").to_string(),
+        )
+        .unwrap()
+    }
+
+    /// Token of a new user with `role`, logged in with a second factor and
+    /// without a temporary password unless the claims are changed.
+    fn login(handler: &AuthHandler, role: Role, edit: impl FnOnce(&mut Claims)) -> (String, Uuid) {
+        let mut claims = Claims {
+            role,
+            id: Uuid::new_v4(),
+            session: Utc::now(),
+            must_change_password: false,
+            mfa: true,
+        };
+        edit(&mut claims);
+
+        (handler.build_token(&claims).unwrap().biscuit, claims.id)
+    }
+
+    fn allowed(handler: &AuthHandler, token: &str, operation: &str, owner: Option<Uuid>) -> bool {
+        handler
+            .authorize(token.to_string(), operation, owner)
+            .unwrap()
+    }
+
+    #[test]
+    fn roles_get_their_rights_only() {
+        let handler = handler();
+        let rights = [
+            ("users:list", [true, true, true, false]),
+            ("users:read", [true, true, true, false]),
+            ("users:write", [true, false, false, false]),
+            ("users:allow", [true, true, false, false]),
+            ("users:delete", [true, false, false, false]),
+            ("users:password_reset", [true, false, true, false]),
+            ("users:mfa_reset", [true, false, false, false]),
+            ("users:export", [true, false, false, false]),
+            ("devices:read", [true, true, true, false]),
+            ("devices:delete", [true, false, false, false]),
+            ("devices:register", [true, false, false, false]),
+            ("lockouts:unlock", [true, false, true, false]),
+            ("api_keys:manage", [true, false, false, false]),
+        ];
+        let roles = [Role::Admin, Role::Staff, Role::Helpdesk, Role::User];
+
+        for (role, column) in roles.into_iter().zip(0..) {
+            let (token, _) = login(&handler, role, |_| {});
+            for (operation, expected) in rights {
+                assert_eq!(
+                    allowed(&handler, &token, operation, None),
+                    expected[column],
+                    "{role} {operation}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn owners_act_on_their_own_resources_only() {
+        let handler = handler();
+        let (token, id) = login(&handler, Role::User, |_| {});
+
+        for operation in [
+            "users:read",
+            "users:password",
+            "devices:add",
+            "devices:delete",
+        ] {
+            assert!(
+                allowed(&handler, &token, operation, Some(id)),
+                "{operation}"
+            );
+            assert!(
+                !allowed(&handler, &token, operation, Some(Uuid::new_v4())),
+                "{operation}"
+            );
+        }
+        assert!(!allowed(&handler, &token, "devices:register", Some(id)));
+    }
+
+    #[test]
+    fn unknown_operations_are_denied() {
+        let handler = handler();
+        let (token, id) = login(&handler, Role::Admin, |_| {});
+
+        assert!(!allowed(&handler, &token, "users:everything", Some(id)));
+    }
+
+    #[test]
+    fn temporary_passwords_only_allow_changing_them() {
+        let handler = handler();
+        let (token, id) = login(&handler, Role::Admin, |e| e.must_change_password = true);
+
+        assert!(allowed(&handler, &token, "users:password", Some(id)));
+        assert!(!allowed(&handler, &token, "users:read", Some(id)));
+        assert!(!allowed(&handler, &token, "users:list", None));
+    }
+
+    #[test]
+    fn mfa_roles_without_a_factor_can_only_enroll() {
+        let handler = handler();
+
+        for role in [Role::Admin, Role::Staff] {
+            let (token, id) = login(&handler, role, |e| e.mfa = false);
+
+            assert!(allowed(&handler, &token, "users:mfa", Some(id)), "{role}");
+            assert!(allowed(&handler, &token, "users:read", Some(id)), "{role}");
+            assert!(
+                allowed(&handler, &token, "users:password", Some(id)),
+                "{role}"
+            );
+            assert!(!allowed(&handler, &token, "users:list", None), "{role}");
+        }
+
+        let (token, _) = login(&handler, Role::Helpdesk, |e| e.mfa = false);
+        assert!(allowed(&handler, &token, "users:list", None));
+    }
+
+    #[test]
+    fn temporary_passwords_are_changed_before_enrolling() {
+        let handler = handler();
+        let (token, id) = login(&handler, Role::Admin, |e| {
+            e.must_change_password = true;
+            e.mfa = false;
+        });
+
+        assert!(allowed(&handler, &token, "users:password", Some(id)));
+        assert!(!allowed(&handler, &token, "users:mfa", Some(id)));
+
+        let (token, id) = login(&handler, Role::Admin, |e| e.mfa = false);
+        assert!(allowed(&handler, &token, "users:mfa", Some(id)));
+    }
+
+    #[test]
+    fn api_keys_give_their_caller_for_their_operations() {
+        let handler = handler();
+        let claims = Claims {
+            role: Role::Helpdesk,
+            id: Uuid::new_v4(),
+            session: Utc::now(),
+            must_change_password: false,
+            mfa: true,
+        };
+        let (key, _) = handler
+            .build_api_key(
+                &claims,
+                Uuid::new_v4(),
+                &["users:password_reset".to_string()],
+                None,
+            )
+            .unwrap();
+
+        assert!(handler.claims(key.clone()).is_err());
+        assert_eq!(
+            handler.caller(key.clone(), "users:password_reset").unwrap(),
+            (claims.id, Role::Helpdesk)
+        );
+        assert!(handler.caller(key, "users:export").is_err());
+    }
+
+    #[test]
+    fn tokens_of_retired_keys_are_invalid() {
+        let mut keys = KeyRing::generate();
+        let first = format!("0 {}", keys.keys[&0].private().to_bytes_hex());
+        keys.rotate().unwrap();
+        assert!(keys.retire(0));
+
+        let (token, _) = login(
+            &handler_with(KeyRing::parse(&first).unwrap()),
+            Role::Admin,
+            |_| {},
+        );
+        let handler = handler_with(keys);
+
+        assert!(matches!(
+            handler.authorize(token, "users:list", None),
+            Err(InvalidToken)
+        ));
+        assert!(matches!(
+            handler.authorize("not a token".to_string(), "users:list", None),
+            Err(InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn key_files_skip_comments_and_blank_lines() {
+        let keys = KeyRing::generate();
+        let key = keys.keys[&0].private().to_bytes_hex();
+        let input = format!("# root keys\n\n3 {key}\n  # retired\n7  {key} \n");
+
+        assert_eq!(KeyRing::parse(&input).unwrap().ids(), [3, 7]);
+    }
+
+    #[test]
+    fn malformed_key_files_are_refused() {
+        let keys = KeyRing::generate();
+        let key = keys.keys[&0].private().to_bytes_hex();
+
+        for input in [
+            String::new(),
+            "# only a comment\n".to_string(),
+            key.clone(),
+            format!("first {key}"),
+            format!("-1 {key}"),
+            "0 not-hex".to_string(),
+            format!("0 {}", &key[2..]),
+        ] {
+            assert!(
+                matches!(KeyRing::parse(&input), Err(KeyRingMalformed)),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn the_newest_key_is_never_retired() {
+        let mut keys = KeyRing::generate();
+
+        assert!(!keys.retire(0));
+        assert_eq!(keys.rotate().unwrap(), 1);
+        assert!(!keys.retire(1));
+        assert!(!keys.retire(5));
+        assert!(keys.retire(0));
+        assert_eq!(keys.ids(), [1]);
+    }
+
+    #[test]
+    fn rotating_past_the_last_id_fails() {
+        let key = KeyRing::generate().keys[&0].private().to_bytes_hex();
+        let mut keys = KeyRing::parse(&format!("{} {key}", u32::MAX)).unwrap();
+
+        assert!(matches!(keys.rotate(), Err(KeyRingFull)));
+        assert_eq!(keys.ids(), [u32::MAX]);
+    }
+
+    #[test]
+    fn tokens_of_older_keys_stay_valid_until_retired() {
+        let mut keys = KeyRing::generate();
+        let first = format!("0 {}", keys.keys[&0].private().to_bytes_hex());
+        keys.rotate().unwrap();
+
+        let (token, _) = login(
+            &handler_with(KeyRing::parse(&first).unwrap()),
+            Role::Admin,
+            |_| {},
+        );
+        let handler = handler_with(keys);
+
+        assert!(allowed(&handler, &token, "users:list", None));
+        let (token, _) = login(&handler, Role::Admin, |_| {});
+        assert!(allowed(&handler, &token, "users:list", None));
+    }
+}
@@ -1,17 +1,26 @@
 use std::collections::VecDeque;
 
 use super::db::DbHandler;
+use crate::auth::{hash, KeyRing};
+use crate::export;
+use crate::import::{parse, ParsedImport};
 use crate::model::device::Device;
-use dialoguer::{theme::ColorfulTheme, Completion, History, Input};
-use futures::executor;
+use crate::model::export::ExportQuery;
+use crate::model::import::{ImportMapping, ImportStatus};
+use crate::model::user::{Role, UserInput, UserQuery};
+use dialoguer::{theme::ColorfulTheme, Completion, History, Input, Password};
+use futures::{executor, StreamExt};
 use lpmng_mq::client::agent::RouterRequest;
 use lpmng_mq::client::Client;
+use serde::de::DeserializeOwned;
+use std::io::Write;
 use tracing::{error, info};
 
 pub struct ConsoleHandler {
     pub db_handler: Option<DbHandler>,
     pub router_address: String,
     pub router: Option<Client>,
+    pub auth_keys_file: Option<String>,
 }
 
 pub static BANNER: &str = "
@@ -37,6 +46,17 @@ fn help() {
     println!("rget / router-get : get authorised macs");
     println!("dbc / db-connect : connect to the database");
     println!("dbu / db-users : get users from the database");
+    println!(
+        "uimp / users-import [file] [field=column...] : import users from a CSV file, fields are \
+username, firstname, lastname, email, phone, delimiter and is_allowed"
+    );
+    println!(
+        "uexp / users-export [file] [option=value...] : export users and their devices, options \
+are format (csv or ndjson), columns and personal"
+    );
+    println!("kl / keys-list : list biscuit root key ids");
+    println!("kr / keys-rotate : add a new signing key (restart the server to use it)");
+    println!("kx / keys-retire [id] : retire an old root key, its tokens become invalid");
     println!("saveme : reset the router and read all devices that have internet true");
     println!("banner : print banner");
     println!();
@@ -53,6 +73,7 @@ async fn router_connect(handler: &mut ConsoleHandler) -> Result<(), String> {
     }
 }
 
+#[allow(clippy::unnecessary_unwrap)]
 async fn router_ping(handler: &mut ConsoleHandler) -> Result<(), String> {
     if handler.router.is_some() {
         if handler.router.as_mut().unwrap().ping().await {
@@ -66,6 +87,7 @@ async fn router_ping(handler: &mut ConsoleHandler) -> Result<(), String> {
     }
 }
 
+#[allow(clippy::unnecessary_unwrap)]
 async fn router_mac_action(
     handler: &mut ConsoleHandler,
     args: &[&str],
@@ -97,6 +119,7 @@ async fn router_mac_action(
     }
 }
 
+#[allow(clippy::unnecessary_unwrap)]
 async fn router_get(handler: &mut ConsoleHandler) -> Result<(), String> {
     if handler.router.is_some() {
         let res = handler
@@ -134,6 +157,7 @@ async fn db_connect(handler: &mut ConsoleHandler) -> Result<(), String> {
     }
 }
 
+#[allow(clippy::unnecessary_unwrap)]
 async fn db_get_users(handler: &mut ConsoleHandler) -> Result<(), String> {
     handler.db_handler = DbHandler::connect()
         .await
@@ -141,22 +165,35 @@ async fn db_get_users(handler: &mut ConsoleHandler) -> Result<(), String> {
         .ok();
 
     if handler.db_handler.is_some() {
-        let users = handler
-            .db_handler
-            .as_mut()
-            .unwrap()
-            .get_users()
-            .await
-            .map_err(|error| format!("{error:?}"))?;
-
         println!("username firstname\tlastname\trole\tis_allowed");
         println!("-----");
 
-        for u in users {
-            println!(
-                "{}\t{}\t\t{}\t{}\t{}",
-                u.username, u.firstname, u.lastname, u.role, u.is_allowed
-            );
+        let mut query = UserQuery {
+            limit: Some(UserQuery::MAX_LIMIT),
+            offset: Some(0),
+            ..Default::default()
+        };
+        loop {
+            let (total, users) = handler
+                .db_handler
+                .as_mut()
+                .unwrap()
+                .get_users(&query)
+                .await
+                .map_err(|error| format!("{error:?}"))?;
+
+            for u in &users {
+                println!(
+                    "{}\t{}\t\t{}\t{}\t{}",
+                    u.username, u.firstname, u.lastname, u.role, u.is_allowed
+                );
+            }
+
+            let offset = query.offset() + users.len() as i64;
+            if users.is_empty() || offset >= total {
+                break;
+            }
+            query.offset = Some(offset);
         }
         Ok(())
     } else {
@@ -164,6 +201,143 @@ async fn db_get_users(handler: &mut ConsoleHandler) -> Result<(), String> {
     }
 }
 
+/// Reads `field=value` arguments into the options type of an api route.
+fn options_value<T: DeserializeOwned>(args: &[&str]) -> Result<T, String> {
+    let options = args
+        .iter()
+        .map(|e| {
+            let (field, value) = e
+                .split_once('=')
+                .ok_or(format!("error: expected field=value, got {e}"))?;
+            let value = match value.parse::<bool>() {
+                Ok(b) => serde_json::Value::Bool(b),
+                Err(_) => serde_json::Value::String(value.to_owned()),
+            };
+            Ok((field.to_owned(), value))
+        })
+        .collect::<Result<serde_json::Map<_, _>, String>>()?;
+
+    serde_json::from_value(serde_json::Value::Object(options))
+        .map_err(|error| format!("invalid options: {error}"))
+}
+
+async fn users_import(handler: &mut ConsoleHandler, args: &[&str]) -> Result<(), String> {
+    let Some(db_handler) = handler.db_handler.as_mut() else {
+        return Err("There is no connection to the database, try command 'dbc'".to_owned());
+    };
+    let Some((file, options)) = args.split_first() else {
+        return Err("error: this command needs a CSV file".to_owned());
+    };
+
+    let mapping: ImportMapping = options_value(options)?;
+
+    let data = std::fs::read(file).map_err(|error| format!("{error:?}"))?;
+    let ParsedImport { rows, rejected } =
+        parse(&data, &mapping).map_err(|error| format!("{error:?}"))?;
+    let mut reports = db_handler
+        .import_users(rows, mapping.is_allowed.unwrap_or(false))
+        .await
+        .map_err(|error| format!("{error:?}"))?;
+    reports.extend(rejected);
+    reports.sort_by_key(|e| e.line);
+
+    println!("line\tstatus\t\tdetail");
+    println!("-----");
+
+    for r in reports {
+        let status = match r.status {
+            ImportStatus::Created => "created",
+            ImportStatus::Updated => "updated",
+            ImportStatus::Rejected => "rejected",
+        };
+        let detail = r
+            .error
+            .or(r.user_id.map(|e| e.to_string()))
+            .unwrap_or_default();
+        println!("{}\t{}\t\t{}", r.line, status, detail);
+    }
+    Ok(())
+}
+
+async fn users_export(handler: &mut ConsoleHandler, args: &[&str]) -> Result<(), String> {
+    let Some(db_handler) = handler.db_handler.as_mut() else {
+        return Err("There is no connection to the database, try command 'dbc'".to_owned());
+    };
+    let Some((file, options)) = args.split_first() else {
+        return Err("error: this command needs a destination file".to_owned());
+    };
+
+    let query: ExportQuery = options_value(options)?;
+    let columns = export::columns(&query).map_err(|error| format!("{error:?}"))?;
+
+    let mut out = std::fs::File::create(file).map_err(|error| format!("{error:?}"))?;
+    let first = export::header(query.format, &columns).map_err(|error| format!("{error:?}"))?;
+    out.write_all(&first)
+        .map_err(|error| format!("{error:?}"))?;
+
+    let mut rows = db_handler.export_users();
+    let mut count = 0;
+    while let Some(row) = rows.next().await {
+        let line = row
+            .and_then(|row| export::line(query.format, &columns, &row))
+            .map_err(|error| format!("{error:?}"))?;
+        out.write_all(&line).map_err(|error| format!("{error:?}"))?;
+        count += 1;
+    }
+
+    println!("{count} rows written to {file}");
+    Ok(())
+}
+
+fn keys_file(handler: &ConsoleHandler) -> Result<&str, String> {
+    handler.auth_keys_file.as_deref().ok_or(
+        "AUTH_KEYS_FILE is not set, keys from AUTH_KEYS must be rotated in the secret store"
+            .to_owned(),
+    )
+}
+
+fn keys_list(handler: &ConsoleHandler) -> Result<(), String> {
+    let keys =
+        KeyRing::load_or_create(keys_file(handler)?).map_err(|error| format!("{error:?}"))?;
+
+    println!("root key ids (the last one signs new tokens) :");
+    for id in keys.ids() {
+        println!("{id}");
+    }
+    Ok(())
+}
+
+fn keys_rotate(handler: &ConsoleHandler) -> Result<(), String> {
+    let path = keys_file(handler)?;
+    let mut keys = KeyRing::load_or_create(path).map_err(|error| format!("{error:?}"))?;
+
+    let id = keys.rotate().map_err(|error| format!("{error:?}"))?;
+    keys.save(path).map_err(|error| format!("{error:?}"))?;
+
+    info!(id, "new root key added");
+    Ok(())
+}
+
+fn keys_retire(handler: &ConsoleHandler, args: &[&str]) -> Result<(), String> {
+    let id = args
+        .first()
+        .and_then(|id| id.parse::<u32>().ok())
+        .ok_or("error: this command need a valid key id".to_owned())?;
+
+    let path = keys_file(handler)?;
+    let mut keys = KeyRing::load_or_create(path).map_err(|error| format!("{error:?}"))?;
+
+    if !keys.retire(id) {
+        return Err(format!(
+            "key {id} does not exist or is the current signing key"
+        ));
+    }
+    keys.save(path).map_err(|error| format!("{error:?}"))?;
+
+    info!(id, "root key retired");
+    Ok(())
+}
+
 async fn saveme(handler: &mut ConsoleHandler) -> Result<(), String> {
     if handler.db_handler.is_none() {
         return Err("Unable to connect to the database".to_owned());
@@ -253,6 +427,11 @@ impl Default for ConsoleCompletion {
                 "rget".to_string(),
                 "dbc".to_string(),
                 "dbu".to_string(),
+                "uimp".to_string(),
+                "uexp".to_string(),
+                "kl".to_string(),
+                "kr".to_string(),
+                "kx".to_string(),
                 "clear".to_string(),
                 "banner".to_string(),
             ],
@@ -297,17 +476,16 @@ async fn _command_executor(cmd: &str, handler: &mut ConsoleHandler) -> Result<()
             router_mac_action(handler, &args[1..], "add", "mac successfully added!").await
         }
         "rrm" | "router-remove" => {
-            router_mac_action(
-                handler,
-                &args[1..],
-                "remove",
-                "mac successfully removed!",
-            )
-            .await
+            router_mac_action(handler, &args[1..], "remove", "mac successfully removed!").await
         }
         "rget" | "router-get" => router_get(handler).await,
         "dbc" | "db-connect" => db_connect(handler).await,
         "dbu" | "db-users" => db_get_users(handler).await,
+        "uimp" | "users-import" => users_import(handler, &args[1..]).await,
+        "uexp" | "users-export" => users_export(handler, &args[1..]).await,
+        "kl" | "keys-list" => keys_list(handler),
+        "kr" | "keys-rotate" => keys_rotate(handler),
+        "kx" | "keys-retire" => keys_retire(handler, &args[1..]),
         "banner" => {
             println!("{}", BANNER);
             Ok(())
@@ -345,3 +523,56 @@ pub async fn console(mut handler: ConsoleHandler) {
             .interact_text();
     }
 }
+
+/// Creates the first admin account, refuses to run once an admin exists.
+pub async fn bootstrap(db_handler: DbHandler) -> Result<(), String> {
+    if db_handler
+        .has_admin()
+        .await
+        .map_err(|error| format!("{error:?}"))?
+    {
+        return Err("an admin account already exists".to_owned());
+    }
+
+    println!("Creating the initial admin account");
+
+    let theme = ColorfulTheme::default();
+    let prompt = |name: &str| {
+        Input::<String>::with_theme(&theme)
+            .with_prompt(name)
+            .interact_text()
+            .map_err(|error| format!("{error:?}"))
+    };
+
+    let username = prompt("username")?;
+    let firstname = prompt("firstname")?;
+    let lastname = prompt("lastname")?;
+    let email = prompt("email")?;
+    let phone = prompt("phone")?;
+    let password = Password::with_theme(&theme)
+        .with_prompt("password")
+        .with_confirmation("confirm password", "passwords do not match")
+        .interact()
+        .map_err(|error| format!("{error:?}"))?;
+
+    // go through the api input type so the same validation rules apply
+    let user: UserInput = serde_json::from_value(serde_json::json!({
+        "username": username,
+        "firstname": firstname,
+        "lastname": lastname,
+        "email": email,
+        "phone": phone,
+        "password": password,
+    }))
+    .map_err(|error| format!("invalid input: {error}"))?;
+
+    let mut user = user.into_unchecked();
+    user.password = hash(user.password);
+    db_handler
+        .insert_user(user, Role::Admin)
+        .await
+        .map_err(|error| format!("{error:?}"))?;
+
+    info!(username, "admin account created");
+    Ok(())
+}
@@ -1,12 +1,39 @@
-use crate::auth::check_hash;
-use crate::error::Error::InvalidCredential;
+use crate::auth::{check_hash, hash, is_hash_obsolete, random_string};
+use crate::error::Error;
+use crate::error::Error::{Conflict, DeviceQuotaReached, InvalidCredential};
 use crate::error::Result;
-use crate::model::device::{Device, NewDevice};
-use crate::model::user::{User, UserInputUnchecked};
+use crate::model::api_key::{ApiKey, NewApiKey};
+use crate::model::device::{Device, DeviceCount, NewDevice};
+use crate::model::export::ExportRow;
+use crate::model::import::{ImportReport, ImportRow, ImportStatus};
+use crate::model::privacy::{AuditEntry, Retention, Session};
+use crate::model::user::{Role, SortOrder, User, UserInputUnchecked, UserQuery};
+use crate::model::utils::{normalize_email, normalize_username, username_skeleton};
+use chrono::NaiveDateTime;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::types::Uuid;
-use sqlx::PgPool;
-use tracing::error;
+use sqlx::{Acquire, PgPool};
+use tracing::{error, info};
+
+/// Turns the violation of a unique constraint into a `Conflict` naming the
+/// field, constraints being named `<table>_<field>_key` by postgres.
+fn unique_violation(error: sqlx::Error) -> Error {
+    if let sqlx::Error::Database(e) = &error {
+        if e.is_unique_violation() {
+            if let Some(field) = e
+                .constraint()
+                .and_then(|e| e.split_once('_'))
+                .and_then(|(_, e)| e.strip_suffix("_key"))
+            {
+                return Conflict(field.to_string());
+            }
+        }
+    }
+
+    error.into()
+}
 
 #[derive(Clone, Debug)]
 pub struct DbHandler {
@@ -31,23 +58,55 @@ impl DbHandler {
             .map_err(Into::into)
     }
 
-    pub async fn insert_device(&self, device: NewDevice) -> Result<()> {
+    /// Inserts the device unless its user already has `max` devices. The user
+    /// row is locked meanwhile so concurrent registrations cannot both fit in
+    /// the last place.
+    pub async fn insert_device(&self, device: NewDevice, max: i64) -> Result<Uuid> {
         let mut tx = self.pool.begin().await?;
 
         sqlx::query!(
             r#"
+                SELECT id FROM users
+                WHERE id=$1
+                FOR UPDATE
+            "#,
+            device.user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let count = sqlx::query!(
+            r#"
+                SELECT count(*) AS "count!" FROM devices
+                WHERE user_id=$1
+            "#,
+            device.user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .count;
+        if count >= max {
+            return Err(DeviceQuotaReached(max));
+        }
+
+        let res = sqlx::query!(
+            r#"
 INSERT INTO devices (mac, user_id, internet, date_time)
 VALUES ($1, $2, $3, $4)
+RETURNING id
         "#,
             device.mac,
             device.user_id,
             device.internet,
             device.date_time
         )
-        .execute(&mut *tx)
-        .await?;
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(unique_violation)?;
 
-        tx.commit().await.map_err(Into::into)
+        tx.commit().await?;
+
+        Ok(res.id)
     }
 
     pub async fn get_devices_by_user_id(&self, id: Uuid) -> Result<Vec<Device>> {
@@ -68,11 +127,76 @@ VALUES ($1, $2, $3, $4)
                 mac: x.mac.to_string(),
                 user_id: x.user_id,
                 internet: x.internet,
+                disabled: x.disabled,
                 date_time: x.date_time,
             })
             .collect())
     }
 
+    pub async fn get_device(&self, id: Uuid) -> Result<Option<Device>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT * FROM devices
+                WHERE id=$1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|x| Device {
+            id: x.id,
+            mac: x.mac.to_string(),
+            user_id: x.user_id,
+            internet: x.internet,
+            disabled: x.disabled,
+            date_time: x.date_time,
+        }))
+    }
+
+    /// Users having devices, with how many.
+    pub async fn get_device_counts(&self) -> Result<Vec<DeviceCount>> {
+        sqlx::query!(
+            r#"
+                SELECT users.id, username, role, max_devices, count(*) AS "devices!"
+                FROM users
+                JOIN devices ON devices.user_id = users.id
+                GROUP BY users.id
+                ORDER BY username
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| {
+            Ok(DeviceCount {
+                user_id: x.id,
+                username: x.username,
+                role: x.role.parse()?,
+                max_devices: x.max_devices,
+                devices: x.devices,
+            })
+        })
+        .collect()
+    }
+
+    pub async fn set_max_devices(&self, id: Uuid, max_devices: Option<i32>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET max_devices = $1
+            WHERE id=$2
+        "#,
+            max_devices,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
     pub async fn get_device_by_mac(&self, mac: String) -> Result<Option<Device>> {
         Ok(sqlx::query!(
             r#"
@@ -88,6 +212,7 @@ VALUES ($1, $2, $3, $4)
             mac: x.mac.to_string(),
             user_id: x.user_id,
             internet: x.internet,
+            disabled: x.disabled,
             date_time: x.date_time,
         }))
     }
@@ -101,17 +226,20 @@ VALUES ($1, $2, $3, $4)
             SET mac = $1,
             user_id = $2,
             internet = $3,
-            date_time = $4
+            date_time = $4,
+            disabled = $6
             WHERE id=$5
         "#,
             device.mac,
             device.user_id,
             device.internet,
             device.date_time,
-            device.id
+            device.id,
+            device.disabled
         )
         .execute(&mut *tx)
-        .await?;
+        .await
+        .map_err(unique_violation)?;
 
         tx.commit().await.map_err(Into::into)
     }
@@ -149,6 +277,7 @@ VALUES ($1, $2, $3, $4)
                 mac: x.mac.to_string(),
                 user_id: x.user_id,
                 internet: x.internet,
+                disabled: x.disabled,
                 date_time: x.date_time,
             });
         }
@@ -156,13 +285,14 @@ VALUES ($1, $2, $3, $4)
         Ok(res)
     }
 
-    pub async fn insert_user(&self, user: UserInputUnchecked) -> Result<()> {
+    pub async fn insert_user(&self, user: UserInputUnchecked, role: Role) -> Result<Uuid> {
         let mut tx = self.pool.begin().await?;
 
-        sqlx::query!(
+        let res = sqlx::query!(
             r#"
-INSERT INTO users (username, firstname, lastname, email, password, phone, role, is_allowed)
-VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+INSERT INTO users (username, firstname, lastname, email, password, phone, role, is_allowed, username_skeleton)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+RETURNING id
         "#,
             user.username,
             user.firstname,
@@ -170,13 +300,112 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
             user.email,
             user.password,
             user.phone,
-            "user",
-            false
+            role.as_str(),
+            false,
+            username_skeleton(&user.username)
         )
-        .execute(&mut *tx)
-        .await?;
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(unique_violation)?;
 
-        tx.commit().await.map_err(Into::into)
+        tx.commit().await?;
+
+        Ok(res.id)
+    }
+
+    /// Creates or updates, matching on the email, the users of an import in
+    /// one transaction. A row failing on a constraint is rolled back alone and
+    /// reported as rejected, other errors abort the whole import.
+    pub async fn import_users(
+        &self,
+        rows: Vec<(u64, ImportRow)>,
+        is_allowed: bool,
+    ) -> Result<Vec<ImportReport>> {
+        let mut tx = self.pool.begin().await?;
+        let mut reports = Vec::new();
+
+        for (line, row) in rows {
+            let mut row_tx = tx.begin().await?;
+
+            let existing = sqlx::query!(
+                r#"
+                    SELECT id FROM users
+                    WHERE lower(email)=lower($1)
+                "#,
+                *row.email
+            )
+            .fetch_optional(&mut *row_tx)
+            .await?;
+
+            let (status, res) = match existing {
+                Some(x) => (
+                    ImportStatus::Updated,
+                    sqlx::query!(
+                        r#"
+                        UPDATE users
+                        SET firstname = $1,
+                        lastname = $2,
+                        phone = $3
+                        WHERE id=$4
+                        RETURNING id
+                    "#,
+                        *row.firstname,
+                        *row.lastname,
+                        *row.phone,
+                        x.id
+                    )
+                    .fetch_one(&mut *row_tx)
+                    .await
+                    .map(|x| x.id),
+                ),
+                None => (
+                    ImportStatus::Created,
+                    sqlx::query!(
+                        r#"
+INSERT INTO users (username, firstname, lastname, email, password, phone, role, is_allowed, username_skeleton)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+RETURNING id
+                    "#,
+                        *row.username,
+                        *row.firstname,
+                        *row.lastname,
+                        *row.email,
+                        hash(random_string(32)),
+                        *row.phone,
+                        Role::User.as_str(),
+                        is_allowed,
+                        username_skeleton(&row.username)
+                    )
+                    .fetch_one(&mut *row_tx)
+                    .await
+                    .map(|x| x.id),
+                ),
+            };
+
+            match res.map_err(unique_violation) {
+                Ok(id) => {
+                    row_tx.commit().await?;
+                    reports.push(ImportReport {
+                        line,
+                        status,
+                        user_id: Some(id),
+                        error: None,
+                    });
+                }
+                Err(Conflict(field)) => {
+                    row_tx.rollback().await?;
+                    reports.push(ImportReport::rejected(
+                        line,
+                        format!("{field} already used"),
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(reports)
     }
 
     pub async fn update_user(&self, user: User) -> Result<()> {
@@ -191,8 +420,10 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
             password = $5,
             phone = $6,
             role = $7 ,
-            is_allowed = $8
-            WHERE id=$9
+            is_allowed = $8,
+            email_verified = $9,
+            username_skeleton = $11
+            WHERE id=$10
         "#,
             user.username,
             user.firstname,
@@ -200,24 +431,36 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
             user.email,
             user.password,
             user.phone,
-            user.role,
+            user.role.as_str(),
             user.is_allowed,
-            user.id
+            user.email_verified,
+            user.id,
+            username_skeleton(&user.username)
         )
         .execute(&mut *tx)
-        .await?;
+        .await
+        .map_err(unique_violation)?;
 
         tx.commit().await.map_err(Into::into)
     }
 
-    pub async fn delete_user(&self, id: Uuid) -> Result<()> {
+    pub async fn update_password(
+        &self,
+        id: Uuid,
+        password: String,
+        must_change_password: bool,
+    ) -> Result<()> {
         let mut tx = self.pool.begin().await?;
 
         sqlx::query!(
             r#"
-            DELETE FROM users
-            WHERE id=$1
+            UPDATE users
+            SET password = $1,
+            must_change_password = $2
+            WHERE id=$3
         "#,
+            password,
+            must_change_password,
             id
         )
         .execute(&mut *tx)
@@ -226,80 +469,944 @@ VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         tx.commit().await.map_err(Into::into)
     }
 
-    pub async fn check_password(&self, login: String, password: String) -> Result<(String, Uuid)> {
-        let res = sqlx::query!(
+    pub async fn get_user_id_by_email(&self, email: String) -> Result<Option<Uuid>> {
+        Ok(sqlx::query!(
             r#"
-                SELECT password, role, id FROM users
-                WHERE username=$1
+                SELECT id FROM users
+                WHERE lower(email)=lower($1)
             "#,
-            login
+            email
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|x| x.id))
+    }
+
+    pub async fn get_user_id_by_oidc_subject(&self, subject: String) -> Result<Option<Uuid>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT id FROM users
+                WHERE oidc_subject=$1
+            "#,
+            subject
         )
         .fetch_optional(&self.pool)
+        .await?
+        .map(|x| x.id))
+    }
+
+    /// Brings names and emails written before their normalization to the
+    /// normalized form and computes the skeletons the unique index compares.
+    /// Fails with a conflict while two accounts have names looking alike or
+    /// the same email.
+    pub async fn normalize_users(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let records = sqlx::query!(
+            r#"
+                SELECT id, username, email FROM users
+                WHERE username_skeleton IS NULL
+            "#
+        )
+        .fetch_all(&mut *tx)
         .await?;
-        match res {
-            Some(x) => {
-                if check_hash(password, x.password.to_string()) {
-                    Ok((x.role.to_string(), x.id))
-                } else {
-                    Err(InvalidCredential)
-                }
-            }
-            None => Err(InvalidCredential),
+
+        for x in records {
+            sqlx::query!(
+                r#"
+                UPDATE users
+                SET username = $1,
+                username_skeleton = $2,
+                email = $3
+                WHERE id=$4
+            "#,
+                normalize_username(&x.username),
+                username_skeleton(&x.username),
+                normalize_email(&x.email),
+                x.id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(unique_violation)?;
         }
+
+        tx.commit().await.map_err(Into::into)
     }
 
-    pub async fn get_user(&self, id: Uuid) -> Result<Option<User>> {
+    /// Whether a user has this name or one looking like it.
+    pub async fn username_exists(&self, username: String) -> Result<bool> {
         let res = sqlx::query!(
             r#"
-                SELECT * FROM users
-                WHERE id=$1
+                SELECT EXISTS(SELECT 1 FROM users WHERE username_skeleton = $1) AS "exists!"
             "#,
+            username_skeleton(&username)
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(res.exists)
+    }
+
+    pub async fn link_oidc_subject(&self, id: Uuid, subject: String) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET oidc_subject = $1
+            WHERE id=$2
+        "#,
+            subject,
             id
         )
-        .fetch_optional(&self.pool)
+        .execute(&mut *tx)
         .await?;
-        Ok(match res {
-            Some(x) => Some(User {
-                id: x.id,
-                username: x.username.to_string(),
-                firstname: x.firstname.to_string(),
-                lastname: x.lastname.to_string(),
-                email: x.email.to_string(),
-                password: x.password.to_string(),
-                phone: x.phone.to_string(),
-                role: x.role.to_string(),
-                is_allowed: x.is_allowed,
-            }),
 
-            None => None,
-        })
+        tx.commit().await.map_err(Into::into)
     }
 
-    pub async fn get_users(&self) -> Result<Vec<User>> {
-        let mut res: Vec<User> = Vec::new();
+    pub async fn update_role(&self, id: Uuid, role: Role) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
 
-        let q = sqlx::query!(
+        sqlx::query!(
             r#"
-                SELECT * FROM users
-            "#
+            UPDATE users
+            SET role = $1
+            WHERE id=$2
+        "#,
+            role.as_str(),
+            id
         )
-        .fetch_all(&self.pool)
+        .execute(&mut *tx)
         .await?;
 
-        for x in &q {
-            res.push(User {
-                id: x.id,
-                username: x.username.to_string(),
-                firstname: x.firstname.to_string(),
-                lastname: x.lastname.to_string(),
-                email: x.email.to_string(),
-                password: x.password.to_string(),
-                phone: x.phone.to_string(),
-                role: x.role.to_string(),
-                is_allowed: x.is_allowed,
-            });
-        }
+        tx.commit().await.map_err(Into::into)
+    }
 
-        Ok(res)
+    /// Marks the email as verified, unless the user changed it since the token was sent.
+    pub async fn verify_email(&self, id: Uuid, email: String) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email_verified = true
+            WHERE id=$1 AND email=$2
+        "#,
+            id,
+            email
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Deletes a user whose devices are already deleted, the history it leaves
+    /// in the audit log loses its ip addresses.
+    pub async fn delete_user(&self, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE audit_log
+            SET ip = NULL
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM users
+            WHERE id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Replaces the personal data of a user and of its devices, keeping the
+    /// rows so attendance statistics stay right.
+    pub async fn anonymize_user(&self, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET username = 'deleted-' || id,
+            username_skeleton = 'deleted-' || id,
+            firstname = '',
+            lastname = '',
+            email = id || '@deleted.invalid',
+            password = '',
+            phone = '',
+            is_allowed = false,
+            email_verified = false,
+            totp_secret = NULL,
+            totp_enabled = false,
+            oidc_subject = NULL,
+            anonymized_at = now()
+            WHERE id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE devices
+            SET mac = 'deleted-' || id,
+            internet = false
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE audit_log
+            SET ip = NULL,
+            detail = NULL
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM email_tokens
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Participants whose retention period is over, staff accounts are kept.
+    pub async fn get_users_to_anonymize(&self) -> Result<Vec<Uuid>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT users.id FROM users, retention
+                WHERE retention.event_end + retention.retention_days <= current_date
+                AND users.role = 'user' AND users.anonymized_at IS NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| x.id)
+        .collect())
+    }
+
+    pub async fn get_retention(&self) -> Result<Retention> {
+        let res = sqlx::query!(
+            r#"
+                SELECT event_end, retention_days FROM retention
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Retention {
+            event_end: res.event_end,
+            retention_days: u16::try_from(res.retention_days).unwrap_or_default(),
+        })
+    }
+
+    pub async fn set_retention(&self, retention: Retention) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE retention
+            SET event_end = $1,
+            retention_days = $2
+        "#,
+            retention.event_end,
+            i32::from(retention.retention_days)
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    pub async fn get_audit_by_user_id(&self, id: Uuid) -> Result<Vec<AuditEntry>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT date_time, action, ip, detail FROM audit_log
+                WHERE user_id=$1
+                ORDER BY date_time
+            "#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| AuditEntry {
+            date_time: x.date_time,
+            action: x.action,
+            ip: x.ip,
+            detail: x.detail,
+        })
+        .collect())
+    }
+
+    pub async fn get_sessions_by_user_id(&self, id: Uuid) -> Result<Vec<Session>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT issued_at, expires_at, revoked_at FROM tokens
+                WHERE user_id=$1
+                ORDER BY issued_at
+            "#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|x| Session {
+            issued_at: x.issued_at,
+            expires_at: x.expires_at,
+            revoked_at: x.revoked_at,
+        })
+        .collect())
+    }
+
+    pub async fn check_password(&self, login: String, password: String) -> Result<User> {
+        let res = sqlx::query!(
+            r#"
+                SELECT * FROM users
+                WHERE username=$1
+            "#,
+            login
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        match res {
+            Some(x) => {
+                if check_hash(password.clone(), x.password.to_string()) {
+                    if is_hash_obsolete(&x.password) {
+                        self.rehash_password(x.id, x.password.clone(), hash(password))
+                            .await?;
+                        info!(user_id = %x.id, "password hash upgraded");
+                    }
+
+                    Ok(User {
+                        id: x.id,
+                        username: x.username.to_string(),
+                        firstname: x.firstname.to_string(),
+                        lastname: x.lastname.to_string(),
+                        email: x.email.to_string(),
+                        password: x.password.to_string(),
+                        phone: x.phone.to_string(),
+                        role: x.role.parse()?,
+                        is_allowed: x.is_allowed,
+                        must_change_password: x.must_change_password,
+                        email_verified: x.email_verified,
+                        totp_secret: x.totp_secret,
+                        totp_enabled: x.totp_enabled,
+                        totp_last_step: x.totp_last_step,
+                        max_devices: x.max_devices,
+                    })
+                } else {
+                    Err(InvalidCredential)
+                }
+            }
+            None => Err(InvalidCredential),
+        }
+    }
+
+    /// Replaces an obsolete hash, unless the password changed in the meantime.
+    async fn rehash_password(&self, id: Uuid, old: String, new: String) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET password = $1
+            WHERE id=$2 AND password=$3
+        "#,
+            new,
+            id,
+            old
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    pub async fn get_user(&self, id: Uuid) -> Result<Option<User>> {
+        let res = sqlx::query!(
+            r#"
+                SELECT * FROM users
+                WHERE id=$1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(match res {
+            Some(x) => Some(User {
+                id: x.id,
+                username: x.username.to_string(),
+                firstname: x.firstname.to_string(),
+                lastname: x.lastname.to_string(),
+                email: x.email.to_string(),
+                password: x.password.to_string(),
+                phone: x.phone.to_string(),
+                role: x.role.parse()?,
+                is_allowed: x.is_allowed,
+                must_change_password: x.must_change_password,
+                email_verified: x.email_verified,
+                totp_secret: x.totp_secret.clone(),
+                totp_enabled: x.totp_enabled,
+                totp_last_step: x.totp_last_step,
+                max_devices: x.max_devices,
+            }),
+
+            None => None,
+        })
+    }
+
+    /// Returns a page of the users matching `query` and how many match in total.
+    pub async fn get_users(&self, query: &UserQuery) -> Result<(i64, Vec<User>)> {
+        let mut res: Vec<User> = Vec::new();
+        let search = query.search_pattern();
+        let desc = matches!(query.order, SortOrder::Desc);
+
+        let total = sqlx::query_scalar!(
+            r#"
+                SELECT count(*) AS "count!" FROM users
+                WHERE ($1::text IS NULL
+                    OR username ILIKE $1 OR firstname ILIKE $1
+                    OR lastname ILIKE $1 OR email ILIKE $1
+                    OR firstname || ' ' || lastname ILIKE $1)
+                AND ($2::text IS NULL OR role = $2)
+                AND ($3::bool IS NULL OR is_allowed = $3)
+            "#,
+            search,
+            query.role.map(Role::as_str),
+            query.is_allowed
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let q = sqlx::query!(
+            r#"
+                SELECT * FROM users
+                WHERE ($1::text IS NULL
+                    OR username ILIKE $1 OR firstname ILIKE $1
+                    OR lastname ILIKE $1 OR email ILIKE $1
+                    OR firstname || ' ' || lastname ILIKE $1)
+                AND ($2::text IS NULL OR role = $2)
+                AND ($3::bool IS NULL OR is_allowed = $3)
+                ORDER BY
+                    CASE WHEN NOT $5 THEN CASE $4
+                        WHEN 'username' THEN username
+                        WHEN 'firstname' THEN firstname
+                        WHEN 'lastname' THEN lastname
+                        WHEN 'email' THEN email
+                        WHEN 'role' THEN role
+                    END END ASC,
+                    CASE WHEN $5 THEN CASE $4
+                        WHEN 'username' THEN username
+                        WHEN 'firstname' THEN firstname
+                        WHEN 'lastname' THEN lastname
+                        WHEN 'email' THEN email
+                        WHEN 'role' THEN role
+                    END END DESC,
+                    id
+                LIMIT $6 OFFSET $7
+            "#,
+            search,
+            query.role.map(Role::as_str),
+            query.is_allowed,
+            query.sort.column(),
+            desc,
+            query.limit(),
+            query.offset()
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for x in &q {
+            res.push(User {
+                id: x.id,
+                username: x.username.to_string(),
+                firstname: x.firstname.to_string(),
+                lastname: x.lastname.to_string(),
+                email: x.email.to_string(),
+                password: x.password.to_string(),
+                phone: x.phone.to_string(),
+                role: x.role.parse()?,
+                is_allowed: x.is_allowed,
+                must_change_password: x.must_change_password,
+                email_verified: x.email_verified,
+                totp_secret: x.totp_secret.clone(),
+                totp_enabled: x.totp_enabled,
+                totp_last_step: x.totp_last_step,
+                max_devices: x.max_devices,
+            });
+        }
+
+        Ok((total, res))
+    }
+
+    /// Streams every user joined with its devices, ordered by username. The
+    /// rows are read by a background task so the stream does not borrow the pool.
+    pub fn export_users(&self) -> mpsc::Receiver<Result<ExportRow>> {
+        let pool = self.pool.clone();
+        let (mut sender, receiver) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            let mut rows = sqlx::query!(
+                r#"
+                    SELECT users.id, username, firstname, lastname, email, phone, role,
+                    is_allowed, devices.id AS "device_id?", mac AS "mac?",
+                    internet AS "internet?", date_time AS "date_time?"
+                    FROM users
+                    LEFT JOIN devices ON devices.user_id = users.id
+                    WHERE anonymized_at IS NULL
+                    ORDER BY username, date_time
+                "#
+            )
+            .fetch(&pool);
+
+            while let Some(row) = rows.next().await {
+                let row = row.map_err(Into::into).map(|x| ExportRow {
+                    user_id: x.id,
+                    username: x.username,
+                    firstname: x.firstname,
+                    lastname: x.lastname,
+                    email: x.email,
+                    phone: x.phone,
+                    role: x.role,
+                    is_allowed: x.is_allowed,
+                    device_id: x.device_id,
+                    mac: x.mac,
+                    internet: x.internet,
+                    device_date_time: x.date_time,
+                });
+                // the receiver is gone when the client disconnected
+                if sender.send(row).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        receiver
+    }
+
+    pub async fn insert_token(
+        &self,
+        revocation_id: String,
+        user_id: Uuid,
+        expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO tokens (revocation_id, user_id, expires_at)
+VALUES ($1, $2, $3)
+        "#,
+            revocation_id,
+            user_id,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    pub async fn revoke_token(&self, revocation_id: String) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE tokens
+            SET revoked_at = now()
+            WHERE revocation_id=$1 AND revoked_at IS NULL
+        "#,
+            revocation_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Revokes every token still valid for this user and returns their revocation ids.
+    pub async fn revoke_user_tokens(&self, user_id: Uuid) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        let records = sqlx::query!(
+            r#"
+            UPDATE tokens
+            SET revoked_at = now()
+            WHERE user_id=$1 AND revoked_at IS NULL AND expires_at > now() AT TIME ZONE 'utc'
+            RETURNING revocation_id
+        "#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(records.into_iter().map(|x| x.revocation_id).collect())
+    }
+
+    /// Revokes the api keys a user created, they carry the rights the user
+    /// had when creating them.
+    pub async fn revoke_user_api_keys(&self, user_id: Uuid) -> Result<Vec<String>> {
+        let records = sqlx::query!(
+            r#"
+            UPDATE tokens
+            SET revoked_at = now()
+            WHERE revocation_id IN (SELECT revocation_id FROM api_keys WHERE created_by=$1)
+                AND revoked_at IS NULL AND expires_at > now() AT TIME ZONE 'utc'
+            RETURNING revocation_id
+        "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records.into_iter().map(|x| x.revocation_id).collect())
+    }
+
+    pub async fn get_revoked_tokens(&self) -> Result<Vec<String>> {
+        let records = sqlx::query!(
+            r#"
+                SELECT revocation_id FROM tokens
+                WHERE revoked_at IS NOT NULL AND expires_at > now() AT TIME ZONE 'utc'
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records.into_iter().map(|x| x.revocation_id).collect())
+    }
+
+    pub async fn insert_email_token(
+        &self,
+        token_hash: String,
+        user_id: Uuid,
+        purpose: &str,
+        email: String,
+        expires_at: NaiveDateTime,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO email_tokens (token_hash, user_id, purpose, email, expires_at)
+VALUES ($1, $2, $3, $4, $5)
+        "#,
+            token_hash,
+            user_id,
+            purpose,
+            email,
+            expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Consumes a still valid email token, returning the user and the address it was sent to.
+    pub async fn use_email_token(
+        &self,
+        token_hash: String,
+        purpose: &str,
+    ) -> Result<Option<(Uuid, String)>> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query!(
+            r#"
+            UPDATE email_tokens
+            SET used_at = now()
+            WHERE token_hash=$1 AND purpose=$2 AND used_at IS NULL AND expires_at > now() AT TIME ZONE 'utc'
+            RETURNING user_id, email
+        "#,
+            token_hash,
+            purpose
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(res.map(|x| (x.user_id, x.email)))
+    }
+
+    /// Stores the secret of an enrollment waiting for its first code.
+    pub async fn set_totp_secret(&self, id: Uuid, secret: String) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET totp_secret = $1
+            WHERE id=$2 AND NOT totp_enabled
+        "#,
+            secret,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Turns on the second factor once the first code was checked, replacing
+    /// the recovery codes.
+    pub async fn enable_totp(&self, id: Uuid, step: i64, code_hashes: Vec<String>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET totp_enabled = true,
+            totp_last_step = $1
+            WHERE id=$2
+        "#,
+            step,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO recovery_codes (code_hash, user_id)
+SELECT *, $2 FROM UNNEST($1::text[])
+        "#,
+            &code_hashes,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    pub async fn disable_totp(&self, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET totp_secret = NULL,
+            totp_enabled = false,
+            totp_last_step = NULL
+            WHERE id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM recovery_codes
+            WHERE user_id=$1
+        "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    /// Records the time step of an accepted code, fails if a later one was already used.
+    pub async fn use_totp_step(&self, id: Uuid, step: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query!(
+            r#"
+            UPDATE users
+            SET totp_last_step = $1
+            WHERE id=$2 AND (totp_last_step IS NULL OR totp_last_step < $1)
+        "#,
+            step,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn use_recovery_code(&self, id: Uuid, code_hash: String) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query!(
+            r#"
+            UPDATE recovery_codes
+            SET used_at = now()
+            WHERE user_id=$1 AND code_hash=$2 AND used_at IS NULL
+        "#,
+            id,
+            code_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Stores the key along with a token row without user, so it is only
+    /// revoked on its own and not with the sessions of its creator.
+    pub async fn insert_api_key(&self, key: NewApiKey) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO tokens (revocation_id, expires_at)
+VALUES ($1, COALESCE($2, 'infinity'::timestamp))
+        "#,
+            key.revocation_id,
+            key.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO api_keys (id, name, created_by, operations, revocation_id, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+            key.id,
+            key.name,
+            key.created_by,
+            &key.operations,
+            key.revocation_id,
+            key.expires_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
+    }
+
+    pub async fn get_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let records = sqlx::query!(
+            r#"
+                SELECT api_keys.*, tokens.revoked_at FROM api_keys
+                JOIN tokens ON tokens.revocation_id = api_keys.revocation_id
+                ORDER BY api_keys.created_at
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(records
+            .into_iter()
+            .map(|x| ApiKey {
+                id: x.id,
+                name: x.name,
+                created_by: x.created_by,
+                operations: x.operations,
+                created_at: x.created_at,
+                expires_at: x.expires_at,
+                revoked_at: x.revoked_at,
+            })
+            .collect())
+    }
+
+    pub async fn get_api_key_revocation_id(&self, id: Uuid) -> Result<Option<String>> {
+        Ok(sqlx::query!(
+            r#"
+                SELECT revocation_id FROM api_keys
+                WHERE id=$1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|x| x.revocation_id))
+    }
+
+    pub async fn has_admin(&self) -> Result<bool> {
+        let res = sqlx::query!(
+            r#"
+                SELECT EXISTS(SELECT 1 FROM users WHERE role = 'admin') AS "exists!"
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(res.exists)
+    }
+
+    pub async fn insert_audit(
+        &self,
+        action: &str,
+        user_id: Option<Uuid>,
+        ip: Option<String>,
+        detail: Option<String>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO audit_log (action, user_id, ip, detail)
+VALUES ($1, $2, $3, $4)
+        "#,
+            action,
+            user_id,
+            ip,
+            detail
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await.map_err(Into::into)
     }
 }
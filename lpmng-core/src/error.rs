@@ -1,5 +1,11 @@
+use serde::Serialize;
 use std::net::{AddrParseError, Ipv4Addr};
 use tracing::error;
+use warp::filters::body::BodyDeserializeError;
+use warp::http::header::{HeaderValue, RETRY_AFTER};
+use warp::http::StatusCode;
+use warp::reject::{InvalidQuery, MethodNotAllowed, MissingHeader, PayloadTooLarge};
+use warp::Reply;
 
 pub type Result<Ok> = core::result::Result<Ok, Error>;
 
@@ -9,10 +15,18 @@ pub enum Error {
     DatabaseError(sqlx::Error),
     InvalidCredential,
     UserDoesNotExist,
+    DeviceDoesNotExist,
     BiscuitError(biscuit_auth::error::Token),
     AuthorizationHeaderMalformed,
     Forbidden,
+    SessionExpired,
+    SessionRevoked,
+    /// The token is not one of ours, corrupted or signed by a retired key.
+    InvalidToken,
     BiscuitMalformed,
+    KeyRingMalformed,
+    /// The newest root key id is `u32::MAX`, old keys have to be renumbered.
+    KeyRingFull,
     NotRunningBehindAProxy,
     NotAnIp(AddrParseError),
     IoError(std::io::Error),
@@ -20,6 +34,35 @@ pub enum Error {
     NoMacForThisIp(Ipv4Addr),
     FailedToExtractMac,
     RouterError(String),
+    TooManyAttempts(u64),
+    LockoutDoesNotExist,
+    MailerError(String),
+    InvalidEmailToken,
+    MfaSecretMalformed,
+    MfaAlreadyEnabled,
+    InvalidMfaCode,
+    ApiKeyDoesNotExist,
+    UnknownOperation(String),
+    OidcDisabled,
+    OidcError(String),
+    OidcStateInvalid,
+    OidcMissingClaim(&'static str),
+    OidcAccountConflict,
+    InvalidCsv(String),
+    ImportColumnMissing(String),
+    UnknownColumn(String),
+    UnknownRole(String),
+    InvalidDeviceQuota(String),
+    /// The user already has as many devices as allowed.
+    DeviceQuotaReached(i64),
+    /// A unique field already has this value, e.g. `email`.
+    Conflict(String),
+    /// The request body failed the validation, `field` being the path of the
+    /// offending field when the error is not about the whole body.
+    InvalidInput {
+        field: Option<String>,
+        message: String,
+    },
 }
 
 impl From<sqlx::Error> for Error {
@@ -54,46 +97,260 @@ impl From<AddrParseError> for Error {
 
 impl warp::reject::Reject for Error {}
 
+/// Body of every error answer of the api.
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    /// Stable identifier clients can match on, e.g. `invalid_credential`.
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError<'a>>,
+}
+
+/// What is wrong with one of the input fields.
+#[derive(Serialize)]
+struct FieldError<'a> {
+    /// Path of the field in the input, unset when the error is about the whole input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+    message: &'a str,
+}
+
+fn error_reply(
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    fields: Vec<FieldError>,
+) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorBody {
+            code,
+            message,
+            fields,
+        }),
+        status,
+    )
+    .into_response()
+}
+
 impl Error {
+    /// Status, code and message the api answers with.
+    fn describe(&self) -> (StatusCode, &'static str, &'static str) {
+        match self {
+            Error::DatabaseError(_)
+            | Error::BiscuitError(_)
+            | Error::BiscuitMalformed
+            | Error::KeyRingMalformed
+            | Error::KeyRingFull
+            | Error::NotRunningBehindAProxy
+            | Error::IoError(_)
+            | Error::RtnetlinkError(_)
+            | Error::NoMacForThisIp(_)
+            | Error::FailedToExtractMac
+            | Error::NotAnIp(_)
+            | Error::RouterError(_)
+            | Error::MailerError(_)
+            | Error::UnknownRole(_)
+            | Error::InvalidDeviceQuota(_)
+            | Error::MfaSecretMalformed => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Internal server error",
+            ),
+            Error::InvalidCredential => (
+                StatusCode::BAD_REQUEST,
+                "invalid_credential",
+                "Invalid username or password",
+            ),
+            Error::UserDoesNotExist => (StatusCode::NOT_FOUND, "user_not_found", "User not found"),
+            Error::DeviceDoesNotExist => (
+                StatusCode::NOT_FOUND,
+                "device_not_found",
+                "Device not found",
+            ),
+            Error::AuthorizationHeaderMalformed => (
+                StatusCode::BAD_REQUEST,
+                "authorization_header_malformed",
+                "Authorization header malformed",
+            ),
+            Error::Forbidden => (StatusCode::FORBIDDEN, "forbidden", "Forbidden"),
+            Error::SessionExpired => (
+                StatusCode::UNAUTHORIZED,
+                "session_expired",
+                "Session expired",
+            ),
+            Error::SessionRevoked => (
+                StatusCode::UNAUTHORIZED,
+                "session_revoked",
+                "Session revoked",
+            ),
+            Error::InvalidToken => (StatusCode::UNAUTHORIZED, "invalid_token", "Invalid token"),
+            Error::TooManyAttempts(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_attempts",
+                "Too many login attempts",
+            ),
+            Error::LockoutDoesNotExist => (
+                StatusCode::NOT_FOUND,
+                "lockout_not_found",
+                "Lockout not found",
+            ),
+            Error::InvalidEmailToken => (
+                StatusCode::BAD_REQUEST,
+                "invalid_email_token",
+                "Invalid or expired token",
+            ),
+            Error::MfaAlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "mfa_already_enabled",
+                "Two-factor authentication already enabled",
+            ),
+            Error::InvalidMfaCode => (StatusCode::BAD_REQUEST, "invalid_mfa_code", "Invalid code"),
+            Error::ApiKeyDoesNotExist => (
+                StatusCode::NOT_FOUND,
+                "api_key_not_found",
+                "API key not found",
+            ),
+            Error::UnknownOperation(_) => (
+                StatusCode::BAD_REQUEST,
+                "unknown_operation",
+                "Unknown operation",
+            ),
+            Error::OidcDisabled => (
+                StatusCode::NOT_FOUND,
+                "oidc_disabled",
+                "OpenID Connect login is not enabled",
+            ),
+            Error::OidcError(_) => (
+                StatusCode::BAD_GATEWAY,
+                "oidc_error",
+                "OpenID Connect login failed",
+            ),
+            Error::OidcStateInvalid => (
+                StatusCode::BAD_REQUEST,
+                "oidc_state_invalid",
+                "Login attempt expired",
+            ),
+            Error::OidcMissingClaim(_) => (
+                StatusCode::BAD_REQUEST,
+                "oidc_missing_claim",
+                "Missing claim from the identity provider",
+            ),
+            Error::OidcAccountConflict => (
+                StatusCode::CONFLICT,
+                "oidc_account_conflict",
+                "An account already uses this email",
+            ),
+            Error::InvalidCsv(_) => (StatusCode::BAD_REQUEST, "invalid_csv", "Malformed CSV file"),
+            Error::ImportColumnMissing(_) => (
+                StatusCode::BAD_REQUEST,
+                "import_column_missing",
+                "Missing column in the CSV file",
+            ),
+            Error::UnknownColumn(_) => {
+                (StatusCode::BAD_REQUEST, "unknown_column", "Unknown column")
+            }
+            Error::DeviceQuotaReached(_) => (
+                StatusCode::FORBIDDEN,
+                "device_quota_reached",
+                "Device quota reached, remove a device first",
+            ),
+            Error::Conflict(_) => (StatusCode::CONFLICT, "conflict", "Value already used"),
+            Error::InvalidInput { .. } => (
+                StatusCode::BAD_REQUEST,
+                "invalid_input",
+                "Invalid request body",
+            ),
+        }
+    }
+
+    /// Details of the errors caused by a given input field.
+    fn fields(&self) -> Vec<FieldError<'_>> {
+        match self {
+            Error::Conflict(field) => vec![FieldError {
+                field: Some(field),
+                message: "already used",
+            }],
+            Error::InvalidInput { field, message } => vec![FieldError {
+                field: field.as_deref(),
+                message,
+            }],
+            Error::UnknownOperation(operation) => vec![FieldError {
+                field: Some("operations"),
+                message: operation,
+            }],
+            Error::UnknownColumn(column) | Error::ImportColumnMissing(column) => {
+                vec![FieldError {
+                    field: Some("columns"),
+                    message: column,
+                }]
+            }
+            _ => vec![],
+        }
+    }
+
     pub async fn handle_warp_rejection(
         rejection: warp::Rejection,
     ) -> core::result::Result<impl warp::Reply, warp::Rejection> {
         if let Some(error) = rejection.find::<Error>() {
             error!(?error, "error while handling request");
 
-            let res = match error {
-                Error::DatabaseError(_)
-                | Error::BiscuitError(_)
-                | Error::BiscuitMalformed
-                | Error::NotRunningBehindAProxy
-                | Error::IoError(_)
-                | Error::RtnetlinkError(_)
-                | Error::NoMacForThisIp(_)
-                | Error::FailedToExtractMac
-                | Error::NotAnIp(_)
-                | Error::RouterError(_) => warp::http::Response::builder()
-                    .status(warp::http::StatusCode::INTERNAL_SERVER_ERROR)
-                    .body("")
-                    .unwrap(),
-                Error::InvalidCredential => warp::http::Response::builder()
-                    .status(warp::http::StatusCode::BAD_REQUEST)
-                    .body("Invalid username or password")
-                    .unwrap(),
-                Error::UserDoesNotExist => warp::http::Response::builder()
-                    .status(warp::http::StatusCode::NOT_FOUND)
-                    .body("User not found")
-                    .unwrap(),
-                Error::AuthorizationHeaderMalformed => warp::http::Response::builder()
-                    .status(warp::http::StatusCode::BAD_REQUEST)
-                    .body("Authorization header malformed")
-                    .unwrap(),
-                Error::Forbidden => warp::http::Response::builder()
-                    .status(warp::http::StatusCode::FORBIDDEN)
-                    .body("Forbidden")
-                    .unwrap(),
-            };
+            let (status, code, message) = error.describe();
+            let mut res = error_reply(status, code, message, error.fields());
+            if let Error::TooManyAttempts(retry_after) = error {
+                res.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+            }
 
             Ok(res)
+        } else if let Some(error) = rejection.find::<BodyDeserializeError>() {
+            Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "invalid_input",
+                "Invalid request body",
+                vec![FieldError {
+                    field: None,
+                    message: &error.to_string(),
+                }],
+            ))
+        } else if rejection.find::<InvalidQuery>().is_some() {
+            Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid query string",
+                vec![],
+            ))
+        } else if let Some(error) = rejection.find::<MissingHeader>() {
+            Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "missing_header",
+                "Missing request header",
+                vec![FieldError {
+                    field: Some(error.name()),
+                    message: "missing",
+                }],
+            ))
+        } else if rejection.find::<PayloadTooLarge>().is_some() {
+            Ok(error_reply(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Request body too large",
+                vec![],
+            ))
+        } else if rejection.find::<MethodNotAllowed>().is_some() {
+            Ok(error_reply(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+                "Method not allowed",
+                vec![],
+            ))
+        } else if rejection.is_not_found() {
+            Ok(error_reply(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "Not found",
+                vec![],
+            ))
         } else {
             Err(rejection)
         }
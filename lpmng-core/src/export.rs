@@ -0,0 +1,178 @@
+use crate::error::Error::UnknownColumn;
+use crate::error::Result;
+use crate::model::export::{ExportColumn, ExportFormat, ExportQuery, ExportRow};
+use csv::WriterBuilder;
+use serde_json::{Map, Value};
+
+/// Resolves the columns asked for in the query, in their order.
+pub fn columns(query: &ExportQuery) -> Result<Vec<ExportColumn>> {
+    let columns = match &query.columns {
+        Some(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(|name| {
+                ExportColumn::ALL
+                    .into_iter()
+                    .find(|e| e.name() == name)
+                    .ok_or(UnknownColumn(name.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => ExportColumn::ALL.to_vec(),
+    };
+
+    Ok(columns
+        .into_iter()
+        .filter(|e| query.personal || !e.is_personal())
+        .collect())
+}
+
+fn value(row: &ExportRow, column: ExportColumn) -> Value {
+    match column {
+        ExportColumn::UserId => row.user_id.to_string().into(),
+        ExportColumn::Username => row.username.clone().into(),
+        ExportColumn::Firstname => row.firstname.clone().into(),
+        ExportColumn::Lastname => row.lastname.clone().into(),
+        ExportColumn::Email => row.email.clone().into(),
+        ExportColumn::Phone => row.phone.clone().into(),
+        ExportColumn::Role => row.role.clone().into(),
+        ExportColumn::IsAllowed => row.is_allowed.into(),
+        ExportColumn::DeviceId => row.device_id.map(|e| e.to_string()).into(),
+        ExportColumn::Mac => row.mac.clone().into(),
+        ExportColumn::Internet => row.internet.into(),
+        ExportColumn::DeviceDateTime => row.device_date_time.map(|e| e.to_string()).into(),
+    }
+}
+
+fn csv_record<I: IntoIterator<Item = String>>(fields: I) -> Result<Vec<u8>> {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    writer.write_record(fields).map_err(std::io::Error::from)?;
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// First line of the export, the column names for a CSV.
+pub fn header(format: ExportFormat, columns: &[ExportColumn]) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Csv => csv_record(columns.iter().map(|e| e.name().to_string())),
+        ExportFormat::Ndjson => Ok(vec![]),
+    }
+}
+
+/// Quotes a value a spreadsheet would read as a formula, user input such as
+/// names must not run when the export is opened. Phones are validated E.164
+/// numbers and keep their leading `+`.
+fn escape_formula(value: String) -> String {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value
+    }
+}
+
+pub fn line(format: ExportFormat, columns: &[ExportColumn], row: &ExportRow) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Csv => csv_record(columns.iter().map(|e| match value(row, *e) {
+            Value::String(value) if e.is_free_text() => escape_formula(value),
+            Value::String(value) => value,
+            Value::Null => String::new(),
+            value => value.to_string(),
+        })),
+        ExportFormat::Ndjson => {
+            let object: Map<String, Value> = columns
+                .iter()
+                .map(|e| (e.name().to_string(), value(row, *e)))
+                .collect();
+            let mut line = Value::Object(object).to_string().into_bytes();
+            line.push(b'\n');
+            Ok(line)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn row() -> ExportRow {
+        ExportRow {
+            user_id: Uuid::nil(),
+            username: "carol".into(),
+            firstname: "=HYPERLINK(\"http://evil\")".into(),
+            lastname: "Smith, Jr".into(),
+            email: "carol@example.com".into(),
+            phone: "+33612345678".into(),
+            role: "user".into(),
+            is_allowed: true,
+            device_id: None,
+            mac: None,
+            internet: None,
+            device_date_time: None,
+        }
+    }
+
+    fn line_of(format: ExportFormat, columns: &[ExportColumn]) -> String {
+        String::from_utf8(line(format, columns, &row()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn csv_lines_quote_formulas() {
+        let columns = [
+            ExportColumn::Username,
+            ExportColumn::Firstname,
+            ExportColumn::Lastname,
+            ExportColumn::Phone,
+            ExportColumn::IsAllowed,
+            ExportColumn::Mac,
+        ];
+
+        assert_eq!(
+            line_of(ExportFormat::Csv, &columns),
+            "carol,\"'=HYPERLINK(\"\"http://evil\"\")\",\"Smith, Jr\",+33612345678,true,\n"
+        );
+    }
+
+    #[test]
+    fn ndjson_lines_keep_the_values() {
+        let columns = [
+            ExportColumn::Firstname,
+            ExportColumn::IsAllowed,
+            ExportColumn::Mac,
+        ];
+        let line: Value = serde_json::from_str(&line_of(ExportFormat::Ndjson, &columns)).unwrap();
+
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "firstname": "=HYPERLINK(\"http://evil\")",
+                "is_allowed": true,
+                "mac": null,
+            })
+        );
+    }
+
+    #[test]
+    fn personal_columns_are_only_exported_when_asked() {
+        let query = ExportQuery {
+            columns: Some("username, email,phone".into()),
+            ..Default::default()
+        };
+        assert!(columns(&query).unwrap() == [ExportColumn::Username]);
+
+        let query = ExportQuery {
+            personal: true,
+            ..query
+        };
+        assert_eq!(columns(&query).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn unknown_columns_are_refused() {
+        let query = ExportQuery {
+            columns: Some("username,password".into()),
+            ..Default::default()
+        };
+
+        assert!(matches!(columns(&query), Err(UnknownColumn(e)) if e == "password"));
+    }
+}
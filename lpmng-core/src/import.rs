@@ -0,0 +1,176 @@
+use crate::error::Error::{ImportColumnMissing, InvalidCsv};
+use crate::error::Result;
+use crate::model::import::{ImportMapping, ImportReport, ImportRow};
+use crate::model::utils::is_reserved_username;
+use csv::{ReaderBuilder, Trim};
+use serde_json::{Map, Value};
+
+/// Rows of a CSV export with their line, the ones failing the validation
+/// being already reported as rejected.
+pub struct ParsedImport {
+    pub rows: Vec<(u64, ImportRow)>,
+    pub rejected: Vec<ImportReport>,
+}
+
+/// Reads a ticketing CSV export.
+pub fn parse(data: &[u8], mapping: &ImportMapping) -> Result<ParsedImport> {
+    if !mapping.delimiter.is_ascii() {
+        return Err(InvalidCsv(
+            "the delimiter must be an ascii character".into(),
+        ));
+    }
+
+    let mut reader = ReaderBuilder::new()
+        .delimiter(mapping.delimiter as u8)
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(data);
+
+    let headers = reader
+        .headers()
+        .map_err(|e| InvalidCsv(e.to_string()))?
+        .clone();
+    let columns = [
+        ("username", &mapping.username),
+        ("firstname", &mapping.firstname),
+        ("lastname", &mapping.lastname),
+        ("email", &mapping.email),
+        ("phone", &mapping.phone),
+    ]
+    .into_iter()
+    .map(|(field, column)| {
+        headers
+            .iter()
+            .position(|e| e == column)
+            .map(|index| (field, index))
+            .ok_or(ImportColumnMissing(column.clone()))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+    let mut rows = Vec::new();
+    let mut rejected = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map(|e| e.line()).unwrap_or_default();
+                rejected.push(ImportReport::rejected(line, e));
+                continue;
+            }
+        };
+        let line = record.position().map(|e| e.line()).unwrap_or_default();
+
+        // go through the api input types so the same validation rules apply
+        let row: Map<String, Value> = columns
+            .iter()
+            .map(|(field, index)| {
+                let value = record.get(*index).unwrap_or_default();
+                (field.to_string(), Value::String(value.to_string()))
+            })
+            .collect();
+
+        match serde_json::from_value::<ImportRow>(Value::Object(row)) {
+            Ok(row) if is_reserved_username(&row.username) => {
+                rejected.push(ImportReport::rejected(line, "username reserved"))
+            }
+            Ok(row) => rows.push((line, row)),
+            Err(e) => rejected.push(ImportReport::rejected(line, e)),
+        }
+    }
+
+    Ok(ParsedImport { rows, rejected })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_rows_with_the_registration_rules() {
+        let data = b"username,firstname,lastname,email,phone\n\
+            Carol,Carol,Smith,Carol@Example.com,06 12 34 56 78\n\
+            dave, Dave ,Jones,dave@example.com,+33612345679\n";
+        let parsed = parse(data, &ImportMapping::default()).unwrap();
+
+        assert!(parsed.rejected.is_empty());
+        assert_eq!(parsed.rows.len(), 2);
+        let (line, row) = &parsed.rows[0];
+        assert_eq!(*line, 2);
+        assert_eq!(*row.username, "carol");
+        assert_eq!(*row.email, "carol@example.com");
+        assert_eq!(*row.phone, "+33612345678");
+        assert_eq!(*parsed.rows[1].1.firstname, "Dave");
+    }
+
+    #[test]
+    fn follows_the_mapping() {
+        let data = "Pseudo;Prénom;Nom;Courriel;Téléphone\n\
+            carol;Carol;Smith;carol@example.com;0612345678\n";
+        let mapping = ImportMapping {
+            username: "Pseudo".into(),
+            firstname: "Prénom".into(),
+            lastname: "Nom".into(),
+            email: "Courriel".into(),
+            phone: "Téléphone".into(),
+            delimiter: ';',
+            is_allowed: None,
+        };
+        let parsed = parse(data.as_bytes(), &mapping).unwrap();
+
+        assert_eq!(parsed.rows.len(), 1);
+        assert_eq!(*parsed.rows[0].1.lastname, "Smith");
+    }
+
+    #[test]
+    fn rejects_invalid_rows_alone() {
+        let data = b"username,firstname,lastname,email,phone\n\
+            carol,Carol,Smith,not an email,0612345678\n\
+            dave,Dave,Jones,dave@example.com,0612345679\n\
+            ca rl,Carl,Smith,carl@example.com,0612345670\n";
+        let parsed = parse(data, &ImportMapping::default()).unwrap();
+
+        assert_eq!(parsed.rows.len(), 1);
+        assert_eq!(*parsed.rows[0].1.username, "dave");
+        let rejected: Vec<_> = parsed.rejected.iter().map(|e| e.line).collect();
+        assert_eq!(rejected, [2, 4]);
+        assert!(parsed.rejected[0]
+            .error
+            .as_ref()
+            .is_some_and(|e| e.contains("invalid email")));
+    }
+
+    #[test]
+    fn rejects_reserved_usernames() {
+        let data = b"username,firstname,lastname,email,phone\n\
+            adm1n,Ada,Min,ada@example.com,0612345678\n\
+            dave,Dave,Jones,dave@example.com,0612345679\n";
+        let parsed = parse(data, &ImportMapping::default()).unwrap();
+
+        assert_eq!(parsed.rows.len(), 1);
+        assert_eq!(parsed.rejected[0].line, 2);
+        assert_eq!(
+            parsed.rejected[0].error.as_deref(),
+            Some("username reserved")
+        );
+    }
+
+    #[test]
+    fn refuses_files_missing_a_column() {
+        let data = b"username,firstname,lastname,email\ncarol,Carol,Smith,carol@example.com\n";
+
+        assert!(matches!(
+            parse(data, &ImportMapping::default()),
+            Err(ImportColumnMissing(column)) if column == "phone"
+        ));
+    }
+
+    #[test]
+    fn refuses_non_ascii_delimiters() {
+        let mapping = ImportMapping {
+            delimiter: '§',
+            ..Default::default()
+        };
+
+        assert!(matches!(parse(b"", &mapping), Err(InvalidCsv(_))));
+    }
+}
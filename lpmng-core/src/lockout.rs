@@ -0,0 +1,242 @@
+use crate::error::Error;
+use crate::error::Error::{LockoutDoesNotExist, TooManyAttempts};
+use crate::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutKind {
+    Username,
+    Ip,
+}
+
+impl FromStr for LockoutKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "username" => Ok(LockoutKind::Username),
+            "ip" => Ok(LockoutKind::Ip),
+            _ => Err(LockoutDoesNotExist),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct Lockout {
+    pub kind: LockoutKind,
+    pub key: String,
+    pub failures: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Tracks failed logins per username and per source ip. Once `max_attempts`
+/// failures are reached the key is locked, for a delay doubling with each new
+/// failure up to `max_delay`.
+pub struct LockoutHandler {
+    max_attempts: u32,
+    delay: Duration,
+    max_delay: Duration,
+    attempts: Mutex<HashMap<(LockoutKind, String), Attempts>>,
+}
+
+impl LockoutHandler {
+    pub fn new(max_attempts: u32, delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            delay,
+            max_delay,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn keys(username: &str, ip: Option<&str>) -> Vec<(LockoutKind, String)> {
+        let mut keys = vec![(LockoutKind::Username, username.to_string())];
+        if let Some(ip) = ip {
+            keys.push((LockoutKind::Ip, ip.to_string()));
+        }
+        keys
+    }
+
+    /// Fails with the number of seconds to wait if the username or the ip is locked.
+    pub fn check(&self, username: &str, ip: Option<&str>) -> Result<()> {
+        let attempts = self.attempts.lock().expect("lockout state poisoned");
+        let now = Utc::now();
+
+        let locked_until = Self::keys(username, ip)
+            .iter()
+            .filter_map(|key| attempts.get(key).and_then(|e| e.locked_until))
+            .filter(|until| *until > now)
+            .max();
+
+        match locked_until {
+            Some(until) => Err(TooManyAttempts((until - now).num_seconds().max(1) as u64)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn failure(&self, username: &str, ip: Option<&str>) {
+        let mut attempts = self.attempts.lock().expect("lockout state poisoned");
+        let now = Utc::now();
+
+        // forget keys that have been quiet for longer than the longest lockout
+        attempts.retain(|_, e| e.last_failure + self.max_delay > now);
+
+        for key in Self::keys(username, ip) {
+            let entry = attempts.entry(key).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.failures >= self.max_attempts {
+                let exponent = (entry.failures - self.max_attempts).min(16);
+                let delay = (self.delay * 2i32.pow(exponent)).min(self.max_delay);
+                entry.locked_until = Some(now + delay);
+            }
+        }
+    }
+
+    pub fn success(&self, username: &str, ip: Option<&str>) {
+        let mut attempts = self.attempts.lock().expect("lockout state poisoned");
+
+        for key in Self::keys(username, ip) {
+            attempts.remove(&key);
+        }
+    }
+
+    pub fn list(&self) -> Vec<Lockout> {
+        let attempts = self.attempts.lock().expect("lockout state poisoned");
+
+        attempts
+            .iter()
+            .map(|((kind, key), e)| Lockout {
+                kind: kind.clone(),
+                key: key.clone(),
+                failures: e.failures,
+                locked_until: e.locked_until,
+            })
+            .collect()
+    }
+
+    pub fn unlock(&self, kind: LockoutKind, key: String) -> bool {
+        let mut attempts = self.attempts.lock().expect("lockout state poisoned");
+
+        attempts.remove(&(kind, key)).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> LockoutHandler {
+        LockoutHandler::new(3, Duration::minutes(1), Duration::minutes(10))
+    }
+
+    /// How long the last failure locked the key for, if it did.
+    fn delay(handler: &LockoutHandler, kind: LockoutKind, key: &str) -> Option<Duration> {
+        let attempts = handler.attempts.lock().unwrap();
+        let e = attempts.get(&(kind, key.to_string()))?;
+
+        e.locked_until.map(|until| until - e.last_failure)
+    }
+
+    #[test]
+    fn keys_are_locked_from_the_threshold_on() {
+        let handler = handler();
+
+        for _ in 0..2 {
+            handler.failure("carol", Some("10.0.0.1"));
+            assert!(handler.check("carol", Some("10.0.0.1")).is_ok());
+        }
+        handler.failure("carol", Some("10.0.0.1"));
+
+        assert!(matches!(
+            handler.check("carol", None),
+            Err(TooManyAttempts(e)) if e > 0 && e <= 60
+        ));
+        assert!(handler.check("bob", Some("10.0.0.1")).is_err());
+        assert!(handler.check("bob", Some("10.0.0.2")).is_ok());
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum() {
+        let handler = handler();
+        let mut delays = vec![];
+
+        for _ in 0..8 {
+            handler.failure("carol", None);
+            delays.push(delay(&handler, LockoutKind::Username, "carol").map(|e| e.num_minutes()));
+        }
+
+        assert_eq!(
+            delays,
+            [
+                None,
+                None,
+                Some(1),
+                Some(2),
+                Some(4),
+                Some(8),
+                Some(10),
+                Some(10)
+            ]
+        );
+    }
+
+    #[test]
+    fn quiet_keys_are_forgotten() {
+        let handler = handler();
+        handler.failure("carol", None);
+        handler.failure("bob", None);
+        handler
+            .attempts
+            .lock()
+            .unwrap()
+            .get_mut(&(LockoutKind::Username, "carol".to_string()))
+            .unwrap()
+            .last_failure -= Duration::minutes(11);
+
+        handler.failure("dave", None);
+
+        let mut keys = handler
+            .list()
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["bob", "dave"]);
+    }
+
+    #[test]
+    fn unlocking_and_success_clear_the_failures() {
+        let handler = handler();
+        for _ in 0..3 {
+            handler.failure("carol", Some("10.0.0.1"));
+        }
+
+        assert!(handler.unlock(LockoutKind::Username, "carol".to_string()));
+        assert!(!handler.unlock(LockoutKind::Username, "carol".to_string()));
+        assert!(handler.check("carol", None).is_ok());
+        assert!(handler.check("carol", Some("10.0.0.1")).is_err());
+
+        handler.success("carol", Some("10.0.0.1"));
+        assert!(handler.check("carol", Some("10.0.0.1")).is_ok());
+        assert!(handler.list().is_empty());
+    }
+}
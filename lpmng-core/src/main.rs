@@ -1,14 +1,19 @@
-use biscuit_auth::KeyPair;
+use chrono::Duration;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use warp::Filter;
 
+use crate::auth::{AuthHandler, KeyRing};
+use crate::lockout::LockoutHandler;
 use crate::mac::MacHandler;
-use api::{api_routes, public_route, ApiHandler};
-use console::{console, ConsoleHandler, BANNER};
+use crate::mailer::{Mailer, OutboxMailer, SmtpMailer};
+use crate::model::device::DeviceQuota;
+use crate::oidc::{OidcConfig, OidcHandler};
+use api::{api_routes, public_route, retention_job, ApiHandler};
+use console::{bootstrap, console, ConsoleHandler, BANNER};
 use lpmng_mq::client::Client;
 
 mod api;
@@ -16,8 +21,14 @@ mod auth;
 mod console;
 mod db;
 mod error;
+mod export;
+mod import;
+mod lockout;
 mod mac;
+mod mailer;
+mod mfa;
 mod model;
+mod oidc;
 
 fn env_abort(env: &'static str) -> impl Fn(std::env::VarError) -> String {
     move |e| {
@@ -49,8 +60,22 @@ async fn main() {
     } else {
         false
     };
+    let bootstrap_mode = args.len() > 1 && args[1] == "bootstrap";
 
-    if console_mode {
+    if bootstrap_mode {
+        let db_handler = match db::DbHandler::connect().await {
+            Ok(db_handler) => db_handler,
+            Err(error) => {
+                error!(?error, "failed to connect to db");
+                panic!();
+            }
+        };
+
+        if let Err(error) = bootstrap(db_handler).await {
+            error!(error, "failed to create the initial admin");
+            std::process::exit(1);
+        }
+    } else if console_mode {
         println!("{}", BANNER);
         console(ConsoleHandler {
             db_handler: db::DbHandler::connect()
@@ -59,17 +84,81 @@ async fn main() {
                 .ok(),
             router_address: router_address.clone(),
             router: Client::connect(&router_address).await,
+            auth_keys_file: std::env::var("AUTH_KEYS_FILE").ok(),
         })
         .await;
     } else {
-        let admin_key = env_get("ADMIN_KEY");
+        let break_glass_key = std::env::var("BREAK_GLASS_KEY").ok();
         let port = match std::env::var("PORT") {
             Ok(p) => p.parse::<u16>().unwrap_or(3030),
             Err(_) => 3030,
         };
+        let token_ttl = match std::env::var("TOKEN_TTL") {
+            Ok(ttl) => ttl.parse::<i64>().unwrap_or(12 * 3600),
+            Err(_) => 12 * 3600,
+        };
+        let session_ttl = match std::env::var("SESSION_TTL") {
+            Ok(ttl) => ttl.parse::<i64>().unwrap_or(4 * 24 * 3600),
+            Err(_) => 4 * 24 * 3600,
+        };
+        let login_max_attempts = match std::env::var("LOGIN_MAX_ATTEMPTS") {
+            Ok(n) => n.parse::<u32>().unwrap_or(5),
+            Err(_) => 5,
+        };
+        let login_lockout_delay = match std::env::var("LOGIN_LOCKOUT_DELAY") {
+            Ok(delay) => delay.parse::<i64>().unwrap_or(30),
+            Err(_) => 30,
+        };
+        let login_lockout_max_delay = match std::env::var("LOGIN_LOCKOUT_MAX_DELAY") {
+            Ok(delay) => delay.parse::<i64>().unwrap_or(3600),
+            Err(_) => 3600,
+        };
+        let reset_max_requests = match std::env::var("RESET_MAX_REQUESTS") {
+            Ok(n) => n.parse::<u32>().unwrap_or(3),
+            Err(_) => 3,
+        };
+        let reset_lockout_delay = match std::env::var("RESET_LOCKOUT_DELAY") {
+            Ok(delay) => delay.parse::<i64>().unwrap_or(300),
+            Err(_) => 300,
+        };
+        let public_url = std::env::var("PUBLIC_URL")
+            .unwrap_or_else(|_| format!("http://localhost:{port}"))
+            .trim_end_matches('/')
+            .to_string();
+        let verify_token_ttl = match std::env::var("VERIFY_TOKEN_TTL") {
+            Ok(ttl) => ttl.parse::<i64>().unwrap_or(24 * 3600),
+            Err(_) => 24 * 3600,
+        };
+        let reset_token_ttl = match std::env::var("RESET_TOKEN_TTL") {
+            Ok(ttl) => ttl.parse::<i64>().unwrap_or(3600),
+            Err(_) => 3600,
+        };
+        let max_devices = match std::env::var("MAX_DEVICES") {
+            Ok(n) => n.parse::<u16>().unwrap_or(3),
+            Err(_) => 3,
+        };
+        let max_devices_by_role = match DeviceQuota::parse_by_role(
+            &std::env::var("MAX_DEVICES_BY_ROLE").unwrap_or_default(),
+        ) {
+            Ok(by_role) => by_role,
+            Err(error) => {
+                error!(?error, "invalid MAX_DEVICES_BY_ROLE");
+                panic!()
+            }
+        };
+        let proxy_hops = match std::env::var("PROXY_HOPS") {
+            Ok(n) => n.parse::<usize>().unwrap_or(1),
+            Err(_) => 1,
+        };
+        let retention_interval = match std::env::var("RETENTION_INTERVAL") {
+            Ok(interval) => interval.parse::<u64>().unwrap_or(3600),
+            Err(_) => 3600,
+        };
         println!("{}", BANNER);
 
-        info!("api keys have been found");
+        if break_glass_key.is_some() {
+            warn!("break-glass admin login is enabled");
+        }
 
         let db_handler = match db::DbHandler::connect().await {
             Ok(db_handler) => db_handler,
@@ -80,6 +169,119 @@ async fn main() {
         };
         info!("database successfully connected");
 
+        if let Err(error) = db_handler.normalize_users().await {
+            error!(
+                ?error,
+                "failed to normalize users, accounts with names looking alike or the same email have to be fixed first"
+            );
+            panic!();
+        }
+
+        let auth_keys = match std::env::var("AUTH_KEYS") {
+            Ok(keys) => KeyRing::parse(&keys),
+            Err(_) => KeyRing::load_or_create(&env_get("AUTH_KEYS_FILE")),
+        };
+        let auth_keys = match auth_keys {
+            Ok(auth_keys) => auth_keys,
+            Err(error) => {
+                error!(?error, "failed to load biscuit root keys");
+                panic!();
+            }
+        };
+        info!(key_ids = ?auth_keys.ids(), "biscuit root keys loaded");
+
+        let policies = match std::env::var("POLICY_FILE") {
+            Ok(path) => match std::fs::read_to_string(&path) {
+                Ok(policies) => policies,
+                Err(error) => {
+                    error!(?error, path, "failed to read the policy file");
+                    panic!();
+                }
+            },
+            Err(_) => include_str!("../policies.dl").to_string(),
+        };
+
+        let revoked = match db_handler.get_revoked_tokens().await {
+            Ok(revoked) => revoked,
+            Err(error) => {
+                error!(?error, "failed to load revoked tokens");
+                panic!();
+            }
+        };
+
+        let auth = match AuthHandler::new(
+            auth_keys,
+            Duration::seconds(token_ttl),
+            Duration::seconds(session_ttl),
+            revoked,
+            policies,
+        ) {
+            Ok(auth) => auth,
+            Err(error) => {
+                error!(?error, "failed to load the authorization policies");
+                panic!();
+            }
+        };
+
+        let mailer: crate::error::Result<Box<dyn Mailer>> = match std::env::var("MAILER").as_deref()
+        {
+            Ok("smtp") => SmtpMailer::new(
+                &env_get("SMTP_URL"),
+                &std::env::var("MAIL_FROM").unwrap_or_else(|_| "lpmng@localhost".into()),
+            )
+            .map(|m| Box::new(m) as Box<dyn Mailer>),
+            _ => OutboxMailer::new(
+                &std::env::var("OUTBOX_DIR").unwrap_or_else(|_| "./outbox".into()),
+            )
+            .map(|m| Box::new(m) as Box<dyn Mailer>),
+        };
+        let mailer = match mailer {
+            Ok(mailer) => mailer,
+            Err(error) => {
+                error!(?error, "failed to init the mailer");
+                panic!();
+            }
+        };
+
+        let oidc = match std::env::var("OIDC_ISSUER") {
+            Ok(issuer) => {
+                let config = OidcConfig {
+                    issuer,
+                    client_id: env_get("OIDC_CLIENT_ID"),
+                    client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+                    redirect_url: std::env::var("OIDC_REDIRECT_URL")
+                        .unwrap_or_else(|_| format!("{public_url}/login/oidc")),
+                    scopes: std::env::var("OIDC_SCOPES")
+                        .unwrap_or_else(|_| "email profile".into())
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect(),
+                    role_claim: std::env::var("OIDC_ROLE_CLAIM").ok(),
+                    role_map: match OidcConfig::parse_role_map(
+                        &std::env::var("OIDC_ROLE_MAP").unwrap_or_default(),
+                    ) {
+                        Ok(role_map) => role_map,
+                        Err(error) => {
+                            error!(?error, "invalid OIDC_ROLE_MAP");
+                            panic!()
+                        }
+                    },
+                };
+
+                match OidcHandler::discover(config).await {
+                    Ok(oidc) => {
+                        info!("openid connect provider discovered");
+                        Some(oidc)
+                    }
+                    Err(error) => {
+                        error!(?error, "failed to discover the openid connect provider");
+                        panic!();
+                    }
+                }
+            }
+            Err(_) => None,
+        };
+
         let mac_handler = match MacHandler::new() {
             Ok(mac_handler) => mac_handler,
             Err(error) => {
@@ -88,21 +290,46 @@ async fn main() {
             }
         };
 
+        let handler = Arc::new(ApiHandler {
+            db: db_handler,
+            auth,
+            break_glass_key,
+            router: Mutex::new(
+                Client::connect(&router_address)
+                    .await
+                    .expect("lpmng router has not been found"),
+            ),
+            mac_handler,
+            lockouts: LockoutHandler::new(
+                login_max_attempts.max(1),
+                Duration::seconds(login_lockout_delay),
+                Duration::seconds(login_lockout_max_delay),
+            ),
+            reset_requests: LockoutHandler::new(
+                reset_max_requests.max(1),
+                Duration::seconds(reset_lockout_delay),
+                Duration::seconds(login_lockout_max_delay),
+            ),
+            mailer,
+            public_url,
+            verify_token_ttl: Duration::seconds(verify_token_ttl),
+            reset_token_ttl: Duration::seconds(reset_token_ttl),
+            oidc,
+            device_quota: DeviceQuota {
+                default: max_devices,
+                by_role: max_devices_by_role,
+            },
+            proxy_hops: proxy_hops.max(1),
+        });
+
+        tokio::spawn(retention_job(
+            handler.clone(),
+            std::time::Duration::from_secs(retention_interval.max(1)),
+        ));
+
         info!("http server starting...");
-        warp::serve(
-            public_route(env_get("PUBLIC_DIR")).or(api_routes(Arc::new(ApiHandler {
-                db: db_handler,
-                auth_key: KeyPair::new().private(),
-                admin_key,
-                router: Mutex::new(
-                    Client::connect(&router_address)
-                        .await
-                        .expect("lpmng router has not been found"),
-                ),
-                mac_handler,
-            }))),
-        )
-        .run(([127, 0, 0, 1], port))
-        .await;
+        warp::serve(public_route(env_get("PUBLIC_DIR")).or(api_routes(handler)))
+            .run(([127, 0, 0, 1], port))
+            .await;
     }
 }
@@ -0,0 +1,55 @@
+use crate::auth::random_string;
+use crate::error::Error::MfaSecretMalformed;
+use crate::error::Result;
+use chrono::Utc;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "lpmng";
+const STEP: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+/// Returns a new base32 encoded TOTP secret.
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+fn totp(secret: &str, username: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| MfaSecretMalformed)?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    )
+    .map_err(|_| MfaSecretMalformed)
+}
+
+/// The `otpauth://` uri authenticator apps enroll from, usually shown as a QR code.
+pub fn otpauth_uri(secret: &str, username: &str) -> Result<String> {
+    Ok(totp(secret, username)?.get_url())
+}
+
+/// Checks `code` against the previous, current and next time steps and returns
+/// the matching one. Steps up to `last_step` are refused so a code cannot be replayed.
+pub fn check_code(secret: &str, code: &str, last_step: Option<i64>) -> Result<Option<i64>> {
+    let totp = totp(secret, "")?;
+    let current = Utc::now().timestamp() as u64 / STEP;
+
+    Ok((current - 1..=current + 1)
+        .map(|step| step as i64)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp.generate(*step as u64 * STEP) == code))
+}
+
+/// One time codes to log in without the authenticator, only their digest is stored.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| random_string(10).to_lowercase())
+        .collect()
+}
@@ -0,0 +1,39 @@
+use crate::model::utils::ValidString;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub created_by: Uuid,
+    pub operations: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyInput {
+    pub name: ValidString,
+    /// Operations of the policies the key is restricted to, e.g. `users:allow`.
+    pub operations: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+pub struct NewApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub created_by: Uuid,
+    pub operations: Vec<String>,
+    pub revocation_id: String,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// Returned once at creation, the key itself is not stored.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    pub id: Uuid,
+    pub key: String,
+}
@@ -1,3 +1,7 @@
+use crate::error::Error::InvalidDeviceQuota;
+use crate::error::Result;
+use crate::model::user::Role;
+use crate::model::utils::Mac;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -9,6 +13,9 @@ pub struct Device {
     pub user_id: Uuid,
     pub internet: bool,
     pub date_time: NaiveDateTime,
+    /// Internet was cut for this device alone, it stays off when its owner
+    /// is allowed again.
+    pub disabled: bool,
 }
 
 #[derive(Deserialize)]
@@ -16,9 +23,121 @@ pub struct DeviceInput {
     pub user_id: Uuid,
 }
 
+#[derive(Deserialize)]
+pub struct MacDeviceInput {
+    pub user_id: Uuid,
+    pub mac: Mac,
+}
+
+/// Admin override of whether a device has internet, whatever its owner is
+/// allowed.
+#[derive(Deserialize)]
+pub struct DevicePatch {
+    pub internet: bool,
+}
+
 pub struct NewDevice {
     pub mac: String,
     pub user_id: Uuid,
     pub internet: bool,
     pub date_time: NaiveDateTime,
 }
+
+/// How many devices users may register, a user's own `max_devices` taking
+/// precedence over the one of their role, then over the global one.
+pub struct DeviceQuota {
+    pub default: u16,
+    pub by_role: Vec<(Role, u16)>,
+}
+
+impl DeviceQuota {
+    /// Parses `MAX_DEVICES_BY_ROLE` entries such as `staff=10,admin=20`.
+    pub fn parse_by_role(input: &str) -> Result<Vec<(Role, u16)>> {
+        input
+            .split(',')
+            .filter_map(|e| e.split_once('='))
+            .map(|(role, max)| {
+                let max = max
+                    .trim()
+                    .parse()
+                    .map_err(|_| InvalidDeviceQuota(max.to_string()))?;
+                Ok((role.trim().parse()?, max))
+            })
+            .collect()
+    }
+
+    pub fn limit(&self, role: Role, max_devices: Option<i32>) -> i64 {
+        match max_devices {
+            Some(max) => max.into(),
+            None => self
+                .by_role
+                .iter()
+                .find(|(e, _)| *e == role)
+                .map_or(self.default, |(_, max)| *max)
+                .into(),
+        }
+    }
+}
+
+/// Sets or, when `None`, clears the device quota of a user.
+#[derive(Deserialize)]
+pub struct QuotaInput {
+    pub max_devices: Option<u16>,
+}
+
+/// Registered devices of a user and how many they may have.
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    pub user_id: Uuid,
+    pub username: String,
+    pub devices: i64,
+    pub max_devices: i64,
+}
+
+/// Registered devices of a user along what their quota is computed from.
+pub struct DeviceCount {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: Role,
+    pub max_devices: Option<i32>,
+    pub devices: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error::UnknownRole;
+
+    fn quota() -> DeviceQuota {
+        DeviceQuota {
+            default: 3,
+            by_role: DeviceQuota::parse_by_role(" staff = 10,admin=20").unwrap(),
+        }
+    }
+
+    #[test]
+    fn users_get_the_quota_of_their_role() {
+        assert_eq!(quota().limit(Role::Staff, None), 10);
+        assert_eq!(quota().limit(Role::Admin, None), 20);
+        assert_eq!(quota().limit(Role::User, None), 3);
+        assert_eq!(quota().limit(Role::Helpdesk, None), 3);
+    }
+
+    #[test]
+    fn a_user_quota_overrides_the_role() {
+        assert_eq!(quota().limit(Role::Staff, Some(1)), 1);
+        assert_eq!(quota().limit(Role::User, Some(0)), 0);
+    }
+
+    #[test]
+    fn invalid_quotas_are_refused() {
+        assert!(matches!(
+            DeviceQuota::parse_by_role("staff=-1"),
+            Err(InvalidDeviceQuota(e)) if e == "-1"
+        ));
+        assert!(matches!(
+            DeviceQuota::parse_by_role("guest=2"),
+            Err(UnknownRole(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportColumn {
+    UserId,
+    Username,
+    Firstname,
+    Lastname,
+    Email,
+    Phone,
+    Role,
+    IsAllowed,
+    DeviceId,
+    Mac,
+    Internet,
+    DeviceDateTime,
+}
+
+impl ExportColumn {
+    pub const ALL: [ExportColumn; 12] = [
+        ExportColumn::UserId,
+        ExportColumn::Username,
+        ExportColumn::Firstname,
+        ExportColumn::Lastname,
+        ExportColumn::Email,
+        ExportColumn::Phone,
+        ExportColumn::Role,
+        ExportColumn::IsAllowed,
+        ExportColumn::DeviceId,
+        ExportColumn::Mac,
+        ExportColumn::Internet,
+        ExportColumn::DeviceDateTime,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportColumn::UserId => "user_id",
+            ExportColumn::Username => "username",
+            ExportColumn::Firstname => "firstname",
+            ExportColumn::Lastname => "lastname",
+            ExportColumn::Email => "email",
+            ExportColumn::Phone => "phone",
+            ExportColumn::Role => "role",
+            ExportColumn::IsAllowed => "is_allowed",
+            ExportColumn::DeviceId => "device_id",
+            ExportColumn::Mac => "mac",
+            ExportColumn::Internet => "internet",
+            ExportColumn::DeviceDateTime => "device_date_time",
+        }
+    }
+
+    /// Columns holding contact details, left out unless asked for.
+    pub fn is_personal(self) -> bool {
+        matches!(self, ExportColumn::Email | ExportColumn::Phone)
+    }
+
+    /// Columns typed in by users, the others are generated or validated.
+    pub fn is_free_text(self) -> bool {
+        matches!(
+            self,
+            ExportColumn::Username
+                | ExportColumn::Firstname
+                | ExportColumn::Lastname
+                | ExportColumn::Email
+        )
+    }
+}
+
+/// Query string of the exports.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ExportQuery {
+    pub format: ExportFormat,
+    /// Comma separated column names, every column when unset.
+    pub columns: Option<String>,
+    /// Whether to include the personal columns such as the email and phone.
+    pub personal: bool,
+}
+
+/// A user joined with one of its devices, users without devices having a
+/// single row with no device.
+pub struct ExportRow {
+    pub user_id: Uuid,
+    pub username: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub phone: String,
+    pub role: String,
+    pub is_allowed: bool,
+    pub device_id: Option<Uuid>,
+    pub mac: Option<String>,
+    pub internet: Option<bool>,
+    pub device_date_time: Option<NaiveDateTime>,
+}
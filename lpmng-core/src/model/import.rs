@@ -0,0 +1,70 @@
+use crate::model::utils::{Email, Phone, Username, ValidString};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Names of the CSV columns holding each field, the ticketing exports do not
+/// all use the same headers.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ImportMapping {
+    pub username: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub phone: String,
+    pub delimiter: char,
+    /// Internet access given to the created accounts, existing ones keep theirs.
+    pub is_allowed: Option<bool>,
+}
+
+impl Default for ImportMapping {
+    fn default() -> Self {
+        Self {
+            username: "username".to_string(),
+            firstname: "firstname".to_string(),
+            lastname: "lastname".to_string(),
+            email: "email".to_string(),
+            phone: "phone".to_string(),
+            delimiter: ',',
+            is_allowed: None,
+        }
+    }
+}
+
+/// A CSV row, checked with the same rules as the registration.
+#[derive(Clone, Deserialize)]
+pub struct ImportRow {
+    pub username: Username,
+    pub firstname: ValidString,
+    pub lastname: ValidString,
+    pub email: Email,
+    pub phone: Phone,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Created,
+    Updated,
+    Rejected,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    /// Line in the file, the header being line 1.
+    pub line: u64,
+    pub status: ImportStatus,
+    pub user_id: Option<Uuid>,
+    pub error: Option<String>,
+}
+
+impl ImportReport {
+    pub fn rejected(line: u64, error: impl ToString) -> Self {
+        Self {
+            line,
+            status: ImportStatus::Rejected,
+            user_id: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
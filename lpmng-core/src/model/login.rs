@@ -1,16 +1,40 @@
-use crate::model::utils::{Username, ValidString};
+use crate::model::user::Role;
+use crate::model::utils::ValidString;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub struct Login {
-    pub username: Username,
+    /// Only normalized at login, names registered before the username rules
+    /// may not pass them.
+    pub username: ValidString,
     pub password: ValidString,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Credentials {
     pub biscuit: String,
-    pub role: String,
+    pub role: Role,
     pub user_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub must_change_password: bool,
+}
+
+/// Answer to a valid password when the account has a second factor.
+#[derive(Serialize)]
+pub struct MfaChallenge {
+    pub mfa_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct MfaLogin {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    pub code: String,
+    pub state: String,
 }
@@ -1,14 +1,22 @@
+pub mod api_key;
 pub mod device;
+pub mod export;
+pub mod import;
 pub mod login;
+pub mod privacy;
 pub mod user;
 
 pub mod utils {
+    use caseless::default_case_fold_str;
     use lazy_static::lazy_static;
+    use phonenumber::{country, Mode};
     use regex::Regex;
     use serde::de::{Error, Visitor};
     use serde::{Deserialize, Deserializer};
     use std::fmt::Formatter;
     use std::ops::Deref;
+    use unicode_normalization::UnicodeNormalization;
+    use unicode_security::{skeleton, RestrictionLevel, RestrictionLevelDetection};
 
     #[derive(Clone, Debug)]
     pub struct ValidString(String);
@@ -59,7 +67,91 @@ pub mod utils {
         }
     }
 
+    /// A username in NFKC and case folded, so names only differing by case or
+    /// by the way they are encoded are the same. Mixing scripts is refused as
+    /// it makes names looking like others.
     impl<'de> Deserialize<'de> for Username {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer
+                .deserialize_string(StringVisitor::normalized(
+                    normalize_username,
+                    vec![
+                        Box::new(not_empty),
+                        Box::new(malformed),
+                        Box::new(len),
+                        Box::new(|str| {
+                            if str.contains(" ") {
+                                Err("username contains space")
+                            } else {
+                                Ok(())
+                            }
+                        }),
+                        Box::new(|str| {
+                            if !str.check_restriction_level(RestrictionLevel::HighlyRestrictive) {
+                                Err("username mixes scripts or has forbidden characters")
+                            } else {
+                                Ok(())
+                            }
+                        }),
+                    ],
+                ))
+                .map(Username)
+        }
+    }
+
+    pub fn normalize_username(str: &str) -> String {
+        let folded = default_case_fold_str(&str.nfkc().collect::<String>());
+        folded.nfkc().collect()
+    }
+
+    /// What a username looks like, names with the same skeleton could be
+    /// taken one for the other and are not both allowed.
+    pub fn username_skeleton(str: &str) -> String {
+        skeleton(&normalize_username(str)).collect()
+    }
+
+    /// Whether a username is kept from registration, `RESERVED_USERNAMES`
+    /// being a comma separated list. Look-alikes such as `adm1n` are kept too,
+    /// digits only look like capitals so the uppercase skeletons are compared
+    /// as well.
+    pub fn is_reserved_username(str: &str) -> bool {
+        let skeletons = |name: &str| {
+            let name = normalize_username(name);
+            [
+                skeleton(&name).collect::<String>(),
+                skeleton(&name.to_uppercase()).collect(),
+            ]
+        };
+        let username = skeletons(str);
+
+        RESERVED_USERNAMES
+            .iter()
+            .any(|e| skeletons(e).iter().zip(&username).any(|(a, b)| a == b))
+    }
+
+    #[derive(Clone)]
+    pub struct Password(String);
+    impl Deref for Password {
+        type Target = String;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    #[allow(clippy::from_over_into)]
+    impl Into<String> for Password {
+        fn into(self) -> String {
+            self.0
+        }
+    }
+
+    /// A new password, checked against the strength policy. Passwords given
+    /// to log in are plain `ValidString`s so older ones keep working.
+    impl<'de> Deserialize<'de> for Password {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>,
@@ -67,17 +159,57 @@ pub mod utils {
             deserializer
                 .deserialize_string(StringVisitor::new(vec![
                     Box::new(not_empty),
-                    Box::new(malformed),
                     Box::new(len),
                     Box::new(|str| {
-                        if str.contains(" ") {
-                            Err("username contains space")
+                        if str.chars().count() < PASSWORD_POLICY.min_length {
+                            Err("password too short")
+                        } else {
+                            Ok(())
+                        }
+                    }),
+                    Box::new(|str| {
+                        let classes = [
+                            str.chars().any(|c| c.is_lowercase()),
+                            str.chars().any(|c| c.is_uppercase()),
+                            str.chars().any(|c| c.is_numeric()),
+                            str.chars().any(|c| !c.is_alphanumeric()),
+                        ];
+
+                        if classes.iter().filter(|e| **e).count() < PASSWORD_POLICY.min_classes {
+                            Err("password needs more kinds of characters")
                         } else {
                             Ok(())
                         }
                     }),
                 ]))
-                .map(Username)
+                .map(Password)
+        }
+    }
+
+    /// Strength rules for new passwords, read once from the environment.
+    struct PasswordPolicy {
+        /// `PASSWORD_MIN_LENGTH`, in characters.
+        min_length: usize,
+        /// `PASSWORD_MIN_CLASSES`, how many of lowercase, uppercase, digits and
+        /// symbols a password must mix.
+        min_classes: usize,
+    }
+
+    impl PasswordPolicy {
+        fn from_env() -> Self {
+            let min_length = match std::env::var("PASSWORD_MIN_LENGTH") {
+                Ok(n) => n.parse::<usize>().unwrap_or(8),
+                Err(_) => 8,
+            };
+            let min_classes = match std::env::var("PASSWORD_MIN_CLASSES") {
+                Ok(n) => n.parse::<usize>().unwrap_or(2),
+                Err(_) => 2,
+            };
+
+            Self {
+                min_length,
+                min_classes,
+            }
         }
     }
 
@@ -98,23 +230,31 @@ pub mod utils {
         }
     }
 
+    /// An email in NFKC and lowercase, providers not telling cases apart.
     impl<'de> Deserialize<'de> for Email {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>,
         {
             deserializer
-                .deserialize_string(StringVisitor::new(vec![Box::new(|str| {
-                    if !EMAIL_REGEX.is_match(str) {
-                        Err("invalid email")
-                    } else {
-                        Ok(())
-                    }
-                })]))
+                .deserialize_string(StringVisitor::normalized(
+                    normalize_email,
+                    vec![Box::new(|str| {
+                        if !EMAIL_REGEX.is_match(str) {
+                            Err("invalid email")
+                        } else {
+                            Ok(())
+                        }
+                    })],
+                ))
                 .map(Email)
         }
     }
 
+    pub fn normalize_email(str: &str) -> String {
+        str.trim().nfkc().collect::<String>().to_lowercase()
+    }
+
     #[derive(Clone)]
     pub struct Phone(String);
     impl Deref for Phone {
@@ -132,34 +272,178 @@ pub mod utils {
         }
     }
 
+    /// A phone number in E.164 form, numbers without a country code being read
+    /// in the default region. Empty when the deployment does not require one
+    /// and none was given.
     impl<'de> Deserialize<'de> for Phone {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_option(PhoneVisitor).map(Phone)
+        }
+    }
+
+    /// Parses a phone number and formats it in E.164, `None` when it is not a
+    /// valid number.
+    pub fn normalize_phone(str: &str) -> Option<String> {
+        phonenumber::parse(Some(PHONE_POLICY.region), str)
+            .ok()
+            .filter(phonenumber::is_valid)
+            .map(|e| e.format().mode(Mode::E164).to_string())
+    }
+
+    /// Phone rules of the deployment, read once from the environment.
+    struct PhonePolicy {
+        /// `PHONE_REGION`, the country of numbers written without a country code.
+        region: country::Id,
+        /// `PHONE_REQUIRED`, whether users must give a phone number.
+        required: bool,
+    }
+
+    impl PhonePolicy {
+        fn from_env() -> Self {
+            let region = match std::env::var("PHONE_REGION") {
+                Ok(r) => r.to_uppercase().parse().unwrap_or(country::FR),
+                Err(_) => country::FR,
+            };
+            let required = match std::env::var("PHONE_REQUIRED") {
+                Ok(b) => b.parse::<bool>().unwrap_or(true),
+                Err(_) => true,
+            };
+
+            Self { region, required }
+        }
+    }
+
+    /// Reads a missing, null or empty phone as no phone.
+    struct PhoneVisitor;
+
+    impl<'de> Visitor<'de> for PhoneVisitor {
+        type Value = String;
+
+        fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+            formatter.write_str("a phone number")
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            self.visit_str("")
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_str(self)
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            if v.trim().is_empty() {
+                if PHONE_POLICY.required {
+                    Err(E::custom("phone required"))
+                } else {
+                    Ok(String::new())
+                }
+            } else {
+                normalize_phone(v).ok_or(E::custom("invalid phone"))
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Mac(String);
+    impl Deref for Mac {
+        type Target = String;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    #[allow(clippy::from_over_into)]
+    impl Into<String> for Mac {
+        fn into(self) -> String {
+            self.0
+        }
+    }
+
+    /// A unicast MAC address, written lowercase with colons like the ones read
+    /// from the neighbour table. Dashes, dots or no separators are accepted.
+    impl<'de> Deserialize<'de> for Mac {
         fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>,
         {
             deserializer
-                .deserialize_string(StringVisitor::new(vec![Box::new(|str| {
-                    if !PHONE_REGEX.is_match(str) {
-                        Err("invalid phone")
-                    } else {
-                        Ok(())
-                    }
-                })]))
-                .map(Phone)
+                .deserialize_string(StringVisitor::normalized(
+                    normalize_mac,
+                    vec![
+                        Box::new(|str| {
+                            if !MAC_REGEX.is_match(str) {
+                                Err("invalid mac")
+                            } else {
+                                Ok(())
+                            }
+                        }),
+                        Box::new(|str| {
+                            let first = u8::from_str_radix(&str[..2], 16).unwrap_or_default();
+                            if first & 1 == 1 || str == "00:00:00:00:00:00" {
+                                Err("not a device mac")
+                            } else {
+                                Ok(())
+                            }
+                        }),
+                    ],
+                ))
+                .map(Mac)
+        }
+    }
+
+    fn normalize_mac(str: &str) -> String {
+        let digits: String = str
+            .trim()
+            .chars()
+            .filter(|c| ![':', '-', '.'].contains(c))
+            .collect::<String>()
+            .to_lowercase();
+
+        if digits.len() == 12 && digits.is_ascii() {
+            (0..12)
+                .step_by(2)
+                .map(|i| &digits[i..i + 2])
+                .collect::<Vec<_>>()
+                .join(":")
+        } else {
+            digits
         }
     }
 
     type CheckFunc = Box<dyn Fn(&str) -> Result<(), &'static str>>;
 
     lazy_static! {
+        static ref MAC_REGEX: Regex = Regex::new(r#"^[0-9a-f]{2}(:[0-9a-f]{2}){5}$"#).unwrap();
+
+        static ref PASSWORD_POLICY: PasswordPolicy = PasswordPolicy::from_env();
+        static ref PHONE_POLICY: PhonePolicy = PhonePolicy::from_env();
+        static ref RESERVED_USERNAMES: Vec<String> = match std::env::var("RESERVED_USERNAMES") {
+            Ok(names) => names,
+            Err(_) => "admin,administrator,root".into(),
+        }
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(normalize_username)
+        .collect();
+
         static ref EMAIL_REGEX: Regex = Regex::new(
     r#"(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])"#,
     )
-    .unwrap();
-
-        static ref PHONE_REGEX: Regex = Regex::new(
-    r#"0[67][\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}|\+33[\s.]?[67][\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}$"#,
-    )
     .unwrap();
     }
 
@@ -188,12 +472,18 @@ pub mod utils {
     }
 
     struct StringVisitor {
+        normalize: fn(&str) -> String,
         rules: Vec<CheckFunc>,
     }
 
     impl StringVisitor {
         fn new(rules: Vec<CheckFunc>) -> Self {
-            Self { rules }
+            Self::normalized(str::to_string, rules)
+        }
+
+        /// Checks the string once rewritten by `normalize`.
+        fn normalized(normalize: fn(&str) -> String, rules: Vec<CheckFunc>) -> Self {
+            Self { normalize, rules }
         }
     }
 
@@ -208,11 +498,119 @@ pub mod utils {
         where
             E: Error,
         {
-            if let Some(error) = self.rules.into_iter().find_map(|f| f(v).err()) {
+            let v = (self.normalize)(v);
+            if let Some(error) = self.rules.into_iter().find_map(|f| f(&v).err()) {
                 Err(E::custom(error))
             } else {
-                Ok(v.to_string())
+                Ok(v)
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde::de::DeserializeOwned;
+
+        /// Reads `value` as a `T` and gives back what is kept of it.
+        fn parse<T: DeserializeOwned + Into<String>>(value: &str) -> Result<String, String> {
+            serde_json::from_value::<T>(value.into())
+                .map(Into::into)
+                .map_err(|e| e.to_string())
+        }
+
+        #[test]
+        fn usernames_are_folded_and_in_nfkc() {
+            assert_eq!(normalize_username("CaRoL"), "carol");
+            assert_eq!(normalize_username("Straße"), "strasse");
+            assert_eq!(normalize_username("ﬁona"), "fiona");
+            assert_eq!(normalize_username("Ｃａｒｏｌ"), "carol");
+        }
+
+        #[test]
+        fn usernames_looking_alike_share_a_skeleton() {
+            assert_eq!(username_skeleton("caro1"), username_skeleton("Carol"));
+            assert_eq!(username_skeleton("cаrol"), username_skeleton("carol"));
+            assert_ne!(username_skeleton("carla"), username_skeleton("carol"));
+        }
+
+        #[test]
+        fn reserved_usernames_include_their_look_alikes() {
+            assert!(is_reserved_username("admin"));
+            assert!(is_reserved_username("ROOT"));
+            assert!(is_reserved_username("adm1n"));
+            assert!(is_reserved_username("rооt"));
+            assert!(is_reserved_username("r00t"));
+            assert!(!is_reserved_username("carol"));
+        }
+
+        #[test]
+        fn usernames_are_checked_once_normalized() {
+            assert_eq!(parse::<Username>("Straße").unwrap(), "strasse");
+            assert_eq!(parse::<Username>("").unwrap_err(), "empty string");
+            assert_eq!(
+                parse::<Username>("ca rol").unwrap_err(),
+                "username contains space"
+            );
+            assert_eq!(
+                parse::<Username>("cаrol").unwrap_err(),
+                "username mixes scripts or has forbidden characters"
+            );
+        }
+
+        #[test]
+        fn emails_are_lowercase_and_in_nfkc() {
+            assert_eq!(normalize_email(" Carol@Example.COM "), "carol@example.com");
+            assert_eq!(
+                parse::<Email>("Ｃarol@example.com").unwrap(),
+                "carol@example.com"
+            );
+            assert_eq!(parse::<Email>("carol").unwrap_err(), "invalid email");
+        }
+
+        #[test]
+        fn phones_are_in_e164() {
+            assert_eq!(normalize_phone("06 12 34 56 78").unwrap(), "+33612345678");
+            assert_eq!(
+                normalize_phone("+33 6.12.34.56.78").unwrap(),
+                "+33612345678"
+            );
+            assert_eq!(normalize_phone("+1 650-253-0000").unwrap(), "+16502530000");
+            assert_eq!(normalize_phone("12345"), None);
+            assert_eq!(parse::<Phone>("not a phone").unwrap_err(), "invalid phone");
+        }
+
+        #[test]
+        fn macs_are_lowercase_with_colons() {
+            assert_eq!(
+                parse::<Mac>("AA-BB-CC-00-11-22").unwrap(),
+                "aa:bb:cc:00:11:22"
+            );
+            assert_eq!(parse::<Mac>("aabb.cc00.1122").unwrap(), "aa:bb:cc:00:11:22");
+            assert_eq!(parse::<Mac>(" aabbcc001122").unwrap(), "aa:bb:cc:00:11:22");
+            assert_eq!(parse::<Mac>("aa:bb:cc:00:11").unwrap_err(), "invalid mac");
+            assert_eq!(
+                parse::<Mac>("01:00:5e:00:00:01").unwrap_err(),
+                "not a device mac"
+            );
+            assert_eq!(
+                parse::<Mac>("00:00:00:00:00:00").unwrap_err(),
+                "not a device mac"
+            );
+        }
+
+        #[test]
+        fn passwords_follow_the_default_policy() {
+            assert_eq!(
+                parse::<Password>("Sh0rt").unwrap_err(),
+                "password too short"
+            );
+            assert_eq!(
+                parse::<Password>("onlylowercase").unwrap_err(),
+                "password needs more kinds of characters"
+            );
+            assert!(parse::<Password>("lowercase1").is_ok());
+            assert!(parse::<Password>("Ünïcödé-pass").is_ok());
+        }
+    }
 }
@@ -0,0 +1,37 @@
+use crate::model::device::Device;
+use crate::model::user::UserView;
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub date_time: NaiveDateTime,
+    pub action: String,
+    pub ip: Option<String>,
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    pub issued_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// Everything stored about a user, as handed to them on request.
+#[derive(Serialize)]
+pub struct PersonalData {
+    pub user: UserView,
+    pub devices: Vec<Device>,
+    pub sessions: Vec<Session>,
+    /// Logins and other recorded actions.
+    pub history: Vec<AuditEntry>,
+}
+
+/// When participants are anonymized, `retention_days` after the end of the
+/// event. Nothing is anonymized while `event_end` is unset.
+#[derive(Serialize, Deserialize)]
+pub struct Retention {
+    pub event_end: Option<NaiveDate>,
+    pub retention_days: u16,
+}
@@ -1,7 +1,51 @@
-use crate::model::utils::{Email, Phone, Username, ValidString};
+use crate::error::Error::UnknownRole;
+use crate::model::login::Credentials;
+use crate::model::utils::{Email, Password, Phone, Username, ValidString};
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use uuid::Uuid;
 
+/// Roles the policies grant rights to, stored as text checked by the database.
+/// Ordered from the least to the most privileged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    Helpdesk,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Helpdesk => "helpdesk",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Role::User, Role::Helpdesk, Role::Staff, Role::Admin]
+            .into_iter()
+            .find(|e| e.as_str() == s)
+            .ok_or(UnknownRole(s.to_string()))
+    }
+}
+
 #[derive(Clone)]
 pub struct User {
     pub id: Uuid,
@@ -11,8 +55,15 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub phone: String,
-    pub role: String,
+    pub role: Role,
     pub is_allowed: bool,
+    pub must_change_password: bool,
+    pub email_verified: bool,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+    /// Overrides the role and global device quota.
+    pub max_devices: Option<i32>,
 }
 
 impl User {
@@ -26,6 +77,10 @@ impl User {
             phone: self.phone,
             role: self.role,
             is_allowed: self.is_allowed,
+            must_change_password: self.must_change_password,
+            email_verified: self.email_verified,
+            mfa_enabled: self.totp_enabled,
+            max_devices: self.max_devices,
         }
     }
 }
@@ -36,7 +91,7 @@ pub struct UserInput {
     pub firstname: ValidString,
     pub lastname: ValidString,
     pub email: Email,
-    pub password: ValidString,
+    pub password: Password,
     pub phone: Phone,
 }
 
@@ -71,8 +126,94 @@ pub struct UserView {
     pub lastname: String,
     pub email: String,
     pub phone: String,
-    pub role: String,
+    pub role: Role,
     pub is_allowed: bool,
+    pub must_change_password: bool,
+    pub email_verified: bool,
+    pub mfa_enabled: bool,
+    pub max_devices: Option<i32>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    #[default]
+    Username,
+    Firstname,
+    Lastname,
+    Email,
+    Role,
+}
+
+impl UserSort {
+    pub fn column(self) -> &'static str {
+        match self {
+            UserSort::Username => "username",
+            UserSort::Firstname => "firstname",
+            UserSort::Lastname => "lastname",
+            UserSort::Email => "email",
+            UserSort::Role => "role",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query string of the user listing, every filter is optional.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UserQuery {
+    /// Matched against the username, names and email, case insensitive.
+    pub search: Option<String>,
+    pub role: Option<Role>,
+    pub is_allowed: Option<bool>,
+    pub sort: UserSort,
+    pub order: SortOrder,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl UserQuery {
+    pub const DEFAULT_LIMIT: i64 = 50;
+    pub const MAX_LIMIT: i64 = 500;
+
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .clamp(1, Self::MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// `ILIKE` pattern of the search, with its wildcards escaped.
+    pub fn search_pattern(&self) -> Option<String> {
+        self.search
+            .as_deref()
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(|e| {
+                let e = e
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                format!("%{e}%")
+            })
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserPage {
+    /// Number of users matching the filters, regardless of the pagination.
+    pub total: i64,
+    pub users: Vec<UserView>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -83,6 +224,50 @@ pub struct UserPatch {
     pub lastname: Option<ValidString>,
     pub email: Option<Email>,
     pub phone: Option<Phone>,
-    pub role: Option<ValidString>,
+    pub role: Option<Role>,
     pub is_allowed: Option<bool>,
 }
+
+#[derive(Clone, Deserialize)]
+pub struct PasswordChange {
+    pub old_password: ValidString,
+    pub new_password: Password,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TemporaryPassword {
+    pub password: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct EmailToken {
+    pub token: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PasswordForgot {
+    pub email: Email,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: Password,
+}
+
+#[derive(Clone, Serialize)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct MfaEnabled {
+    pub recovery_codes: Vec<String>,
+    pub credentials: Credentials,
+}
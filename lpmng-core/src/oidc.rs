@@ -0,0 +1,394 @@
+use crate::error::Error::{OidcError, OidcMissingClaim, OidcStateInvalid};
+use crate::error::Result;
+use crate::model::user::Role;
+use crate::model::utils::{normalize_email, normalize_phone};
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::core::{
+    CoreAuthDisplay, CoreAuthPrompt, CoreAuthenticationFlow, CoreErrorResponseType,
+    CoreGenderClaim, CoreJsonWebKey, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm,
+    CoreProviderMetadata, CoreRevocableToken, CoreRevocationErrorResponse,
+    CoreTokenIntrospectionResponse, CoreTokenType,
+};
+use openidconnect::{
+    reqwest, AdditionalClaims, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
+    EmptyExtraTokenFields, EndpointMaybeSet, EndpointNotSet, EndpointSet, IdTokenClaims,
+    IdTokenFields, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    StandardErrorResponse, StandardTokenResponse, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Claims of the ID token not covered by the standard ones, where the role claim is looked up.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OtherClaims(HashMap<String, serde_json::Value>);
+impl AdditionalClaims for OtherClaims {}
+
+type OidcTokenResponse = StandardTokenResponse<
+    IdTokenFields<
+        OtherClaims,
+        EmptyExtraTokenFields,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+    >,
+    CoreTokenType,
+>;
+
+type OidcClient = Client<
+    OtherClaims,
+    CoreAuthDisplay,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJsonWebKey,
+    CoreAuthPrompt,
+    StandardErrorResponse<CoreErrorResponseType>,
+    OidcTokenResponse,
+    CoreTokenIntrospectionResponse,
+    CoreRevocableToken,
+    CoreRevocationErrorResponse,
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Front page the provider sends the user back to, which posts the code to the callback.
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Claim holding the groups or roles of the user, roles are left alone when unset.
+    pub role_claim: Option<String>,
+    /// `(claim value, role)` pairs, the first value found in the claim gives the role.
+    pub role_map: Vec<(String, Role)>,
+}
+
+impl OidcConfig {
+    /// Parses `OIDC_ROLE_MAP` entries such as `lan-admins=admin,lan-staff=staff`.
+    pub fn parse_role_map(input: &str) -> Result<Vec<(String, Role)>> {
+        input
+            .split(',')
+            .filter_map(|e| e.split_once('='))
+            .map(|(value, role)| Ok((value.trim().to_string(), role.trim().parse()?)))
+            .collect()
+    }
+}
+
+/// What the provider tells about the user once the login is verified.
+pub struct Identity {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub username: Option<String>,
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub phone: Option<String>,
+    /// Mapped role, `None` when no role claim is configured or none of its
+    /// values is in the map, so the role is then left alone.
+    pub role: Option<Role>,
+    /// Whether the provider reports a multi-factor authentication.
+    pub mfa: bool,
+}
+
+struct Pending {
+    verifier: PkceCodeVerifier,
+    nonce: Nonce,
+    created_at: DateTime<Utc>,
+}
+
+/// Authorization code flow with PKCE against an OpenID Connect provider.
+pub struct OidcHandler {
+    client: OidcClient,
+    http: reqwest::Client,
+    scopes: Vec<String>,
+    role_claim: Option<String>,
+    role_map: Vec<(String, Role)>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl OidcHandler {
+    /// Fetches the provider metadata from `<issuer>/.well-known/openid-configuration`.
+    pub async fn discover(config: OidcConfig) -> Result<Self> {
+        let http = reqwest::ClientBuilder::new()
+            // following redirects would expose the client to SSRF
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| OidcError(e.to_string()))?;
+
+        let issuer = IssuerUrl::new(config.issuer).map_err(|e| OidcError(e.to_string()))?;
+        let metadata = CoreProviderMetadata::discover_async(issuer, &http)
+            .await
+            .map_err(|e| OidcError(e.to_string()))?;
+
+        let client = OidcClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id),
+            config.client_secret.map(ClientSecret::new),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.redirect_url).map_err(|e| OidcError(e.to_string()))?,
+        );
+
+        Ok(Self {
+            client,
+            http,
+            scopes: config.scopes,
+            role_claim: config.role_claim,
+            role_map: config.role_map,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the provider url to send the user to, remembering the PKCE
+    /// verifier and nonce of this attempt until the callback.
+    pub fn authorize_url(&self) -> String {
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+
+        let mut request = self.client.authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        );
+        for scope in &self.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (url, state, nonce) = request.set_pkce_challenge(challenge).url();
+
+        let mut pending = self.pending.lock().expect("oidc state poisoned");
+        let now = Utc::now();
+        pending.retain(|_, e| e.created_at + Duration::minutes(10) > now);
+        pending.insert(
+            state.secret().clone(),
+            Pending {
+                verifier,
+                nonce,
+                created_at: now,
+            },
+        );
+
+        url.to_string()
+    }
+
+    /// Trades the code of the callback for the verified identity of the user.
+    pub async fn exchange(&self, code: String, state: String) -> Result<Identity> {
+        let pending = self
+            .pending
+            .lock()
+            .expect("oidc state poisoned")
+            .remove(&state)
+            .filter(|e| e.created_at + Duration::minutes(10) > Utc::now())
+            .ok_or(OidcStateInvalid)?;
+
+        let response = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .map_err(|e| OidcError(e.to_string()))?
+            .set_pkce_verifier(pending.verifier)
+            .request_async(&self.http)
+            .await
+            .map_err(|e| OidcError(e.to_string()))?;
+
+        let id_token = response
+            .id_token()
+            .ok_or(OidcError("no id token in the response".into()))?;
+        let claims = id_token
+            .claims(&self.client.id_token_verifier(), &pending.nonce)
+            .map_err(|e| OidcError(e.to_string()))?;
+
+        self.identity(claims)
+    }
+
+    fn identity(&self, claims: &IdTokenClaims<OtherClaims, CoreGenderClaim>) -> Result<Identity> {
+        let role = self.role_claim.as_ref().and_then(|claim| {
+            let values: Vec<String> = match claims.additional_claims().0.get(claim) {
+                Some(serde_json::Value::String(value)) => vec![value.clone()],
+                Some(serde_json::Value::Array(values)) => values
+                    .iter()
+                    .filter_map(|e| e.as_str().map(str::to_string))
+                    .collect(),
+                _ => vec![],
+            };
+
+            self.role_map
+                .iter()
+                .find(|(value, _)| values.contains(value))
+                .map(|(_, role)| *role)
+        });
+
+        Ok(Identity {
+            subject: claims.subject().to_string(),
+            email: claims
+                .email()
+                .map(|e| normalize_email(e))
+                .ok_or(OidcMissingClaim("email"))?,
+            email_verified: claims.email_verified().unwrap_or(false),
+            username: claims.preferred_username().map(|e| e.to_string()),
+            firstname: claims
+                .given_name()
+                .and_then(|e| e.get(None))
+                .map(|e| e.to_string()),
+            lastname: claims
+                .family_name()
+                .and_then(|e| e.get(None))
+                .map(|e| e.to_string()),
+            phone: claims.phone_number().and_then(|e| normalize_phone(e)),
+            role,
+            mfa: claims.auth_method_refs().is_some_and(|amr| {
+                amr.iter()
+                    .any(|e| ["mfa", "otp", "hwk"].contains(&e.as_str()))
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openidconnect::core::{CoreJsonWebKeySet, CoreRsaPrivateSigningKey};
+    use openidconnect::{
+        Audience, EndUserEmail, EndUserUsername, IdToken, JsonWebKeyId, PrivateSigningKey,
+        StandardClaims, SubjectIdentifier,
+    };
+    use std::sync::Arc;
+    use warp::Filter;
+
+    const KEY: &str = include_str!("../tests/fixtures/oidc_key.pem");
+
+    /// Starts a provider answering discovery, keys and code exchanges, its ID
+    /// tokens carrying `groups` and the nonce of the pending authorization.
+    /// Returns the handler configured against it.
+    async fn mock_provider(groups: serde_json::Value) -> (OidcHandler, Arc<Mutex<String>>) {
+        let key = Arc::new(
+            CoreRsaPrivateSigningKey::from_pem(KEY, Some(JsonWebKeyId::new("test".into())))
+                .unwrap(),
+        );
+        let issuer = Arc::new(Mutex::new(String::new()));
+        let nonce = Arc::new(Mutex::new(String::new()));
+
+        let metadata = {
+            let issuer = issuer.clone();
+            warp::path!(".well-known" / "openid-configuration").map(move || {
+                let issuer = issuer.lock().unwrap().clone();
+                warp::reply::json(&serde_json::json!({
+                    "issuer": issuer,
+                    "authorization_endpoint": format!("{issuer}/authorize"),
+                    "token_endpoint": format!("{issuer}/token"),
+                    "jwks_uri": format!("{issuer}/jwks"),
+                    "response_types_supported": ["code"],
+                    "subject_types_supported": ["public"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                }))
+            })
+        };
+
+        let jwks = {
+            let key = key.clone();
+            warp::path!("jwks").map(move || {
+                warp::reply::json(&CoreJsonWebKeySet::new(vec![key.as_verification_key()]))
+            })
+        };
+
+        let token = {
+            let issuer = issuer.clone();
+            let nonce = nonce.clone();
+            warp::post().and(warp::path!("token")).map(move || {
+                let claims = IdTokenClaims::new(
+                    IssuerUrl::new(issuer.lock().unwrap().clone()).unwrap(),
+                    vec![Audience::new("lpmng".into())],
+                    Utc::now() + Duration::minutes(5),
+                    Utc::now(),
+                    StandardClaims::new(SubjectIdentifier::new("subject".into()))
+                        .set_email(Some(EndUserEmail::new(" Carol@Example.com".into())))
+                        .set_email_verified(Some(true))
+                        .set_preferred_username(Some(EndUserUsername::new("carol".into()))),
+                    serde_json::from_value(serde_json::json!({ "groups": groups })).unwrap(),
+                )
+                .set_nonce(Some(Nonce::new(nonce.lock().unwrap().clone())));
+                let id_token = IdToken::<
+                    OtherClaims,
+                    CoreGenderClaim,
+                    CoreJweContentEncryptionAlgorithm,
+                    CoreJwsSigningAlgorithm,
+                >::new(
+                    claims,
+                    key.as_ref(),
+                    CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                    None,
+                    None,
+                )
+                .unwrap();
+
+                warp::reply::json(&serde_json::json!({
+                    "access_token": "access",
+                    "token_type": "bearer",
+                    "id_token": id_token.to_string(),
+                }))
+            })
+        };
+
+        let (addr, server) =
+            warp::serve(metadata.or(jwks).or(token)).bind_ephemeral(([127, 0, 0, 1], 0));
+        *issuer.lock().unwrap() = format!("http://{addr}");
+        tokio::spawn(server);
+
+        let handler = OidcHandler::discover(OidcConfig {
+            issuer: format!("http://{addr}"),
+            client_id: "lpmng".into(),
+            client_secret: None,
+            redirect_url: "http://localhost/login/oidc".into(),
+            scopes: vec!["email".into()],
+            role_claim: Some("groups".into()),
+            role_map: OidcConfig::parse_role_map("lan-admins=admin,lan-staff=staff").unwrap(),
+        })
+        .await
+        .unwrap();
+
+        (handler, nonce)
+    }
+
+    /// Goes through an authorization and its callback.
+    async fn login(handler: &OidcHandler, nonce: &Mutex<String>) -> Result<Identity> {
+        let url = openidconnect::url::Url::parse(&handler.authorize_url()).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        *nonce.lock().unwrap() = query["nonce"].clone();
+
+        handler
+            .exchange("code".into(), query["state"].clone())
+            .await
+    }
+
+    #[tokio::test]
+    async fn maps_the_role_claim() {
+        let (handler, nonce) = mock_provider(serde_json::json!(["other", "lan-staff"])).await;
+        let identity = login(&handler, &nonce).await.unwrap();
+
+        assert_eq!(identity.subject, "subject");
+        assert_eq!(identity.email, "carol@example.com");
+        assert!(identity.email_verified);
+        assert_eq!(identity.username.as_deref(), Some("carol"));
+        assert_eq!(identity.role, Some(Role::Staff));
+    }
+
+    #[tokio::test]
+    async fn leaves_the_role_alone_when_no_value_is_mapped() {
+        let (handler, nonce) = mock_provider(serde_json::json!("other")).await;
+        let identity = login(&handler, &nonce).await.unwrap();
+
+        assert_eq!(identity.role, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_unknown_state() {
+        let (handler, _) = mock_provider(serde_json::json!([])).await;
+
+        assert!(matches!(
+            handler.exchange("code".into(), "forged".into()).await,
+            Err(OidcStateInvalid)
+        ));
+    }
+}
@@ -4,6 +4,25 @@ import { Device, DeviceInput } from "../models/device.js";
 import { router } from "./router.js";
 
 const base = "/api";
+/** Largest page the api serves. */
+const usersPageSize = 500;
+
+/**
+ * Reads the error envelope of the api into a message for the user.
+ * @param {Response} res
+ * @returns {Promise<string>}
+ */
+async function errorMessage(res) {
+  try {
+    const { message, fields = [] } = await res.json();
+    const details = fields.map((e) =>
+      e.field ? `${e.field} : ${e.message}` : e.message,
+    );
+    return [message, ...details].join("\n");
+  } catch {
+    return res.statusText;
+  }
+}
 
 class Lpmng {
   /** @type ?Credentials */
@@ -33,7 +52,7 @@ class Lpmng {
     });
 
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
 
     const creds = Credentials.fromJson(await res.json());
@@ -65,8 +84,18 @@ class Lpmng {
       method: "POST",
     });
 
+    if (res.status === 409) {
+      const { fields } = await res.json();
+      const field = fields[0]?.field;
+      const labels = {
+        username: "ce pseudo",
+        email: "cet email",
+        phone: "ce numéro de téléphone",
+      };
+      throw `${labels[field] ?? field} est déjà utilisé`;
+    }
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
 
     await this.login(new Login(user.username, user.password));
@@ -89,7 +118,7 @@ class Lpmng {
     });
 
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
   }
 
@@ -112,7 +141,7 @@ class Lpmng {
     });
 
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
 
     return UserView.fromJson(await res.json());
@@ -152,7 +181,7 @@ class Lpmng {
     });
 
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
 
     /** @type any[] */
@@ -171,21 +200,35 @@ class Lpmng {
       throw "non autorisé";
     }
 
-    const res = await fetch(`${base}/users`, {
-      headers: {
-        Authorization: `Bearer ${this.creds.biscuit}`,
-        "Access-Control-Request-Method": "GET",
-      },
-    });
-
-    if (!res.ok) {
-      throw await res.text();
-    }
-
+    // the server answers by pages, fetch them all
     /** @type any[] */
-    const list = await res.json();
+    const users = [];
+    let total = 0;
+    do {
+      const res = await fetch(
+        `${base}/users?limit=${usersPageSize}&offset=${users.length}`,
+        {
+          headers: {
+            Authorization: `Bearer ${this.creds.biscuit}`,
+            "Access-Control-Request-Method": "GET",
+          },
+        },
+      );
+
+      if (!res.ok) {
+        throw await errorMessage(res);
+      }
+
+      /** @type {{total: number, users: any[]}} */
+      const page = await res.json();
+      if (page.users.length === 0) {
+        break;
+      }
+      users.push(...page.users);
+      total = page.total;
+    } while (users.length < total);
 
-    return list.map((e) => UserView.fromJson(e));
+    return users.map((e) => UserView.fromJson(e));
   }
 
   /**
@@ -211,7 +254,7 @@ class Lpmng {
     });
 
     if (!res.ok) {
-      throw await res.text();
+      throw await errorMessage(res);
     }
   }
 }
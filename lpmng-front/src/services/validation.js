@@ -2,8 +2,8 @@ import { Err, Ok } from "../models/result.js";
 
 const emailRegex =
   /(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\.)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])/;
-const phoneRegex =
-  /0[67][\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}|\+33[\s.]?[67][\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}[\s.]?\d{2}/;
+// only the shape is checked, the server knows the numbering plans
+const phoneRegex = /^(\+|00)?[\d\s.()-]{6,20}$/;
 
 /**
  * @param {string} str
@@ -107,7 +107,7 @@ class Phone extends Validator {
   constructor() {
     super([
       (str) => {
-        if (!phoneRegex.test(str)) {
+        if (str.length > 0 && !phoneRegex.test(str)) {
           return new Err("numéro de téléphone invalide");
         } else {
           return new Ok(null);